@@ -0,0 +1,161 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::input::ZIP_MEMBER_SEP;
+
+/// Extensions of compressed files, removed before the `.dta` extension
+const COMPRESSED_EXTS: &[&str] = &["gz", "zst", "zstd"];
+
+/// An input file of a batch conversion
+#[derive(Debug, Clone)]
+pub struct BatchInput {
+    /// The input as given to `open_source`
+    pub path: PathBuf,
+    /// The path of the file relative to the directory or glob it was
+    /// found under, without its `.dta` and compression extensions
+    pub relpath: PathBuf,
+}
+
+impl BatchInput {
+    fn new(path: PathBuf, rel: &Path) -> BatchInput {
+        let relpath = match rel.file_name() {
+            Some(name) => rel.with_file_name(strip_extensions(&name.to_string_lossy())),
+            None => rel.to_path_buf(),
+        };
+        BatchInput { path, relpath }
+    }
+}
+
+/// Expand the inputs of a batch conversion
+///
+/// Each spec is a file (possibly a zip member `archive.zip::path/in/zip.dta`),
+/// a directory, which is searched recursively for `.dta` files, or a glob
+/// pattern such as `data/*/wave?.dta`. Files are returned in the order of
+/// the specs, and in path order within a directory or pattern.
+pub fn expand_inputs(specs: &[String]) -> Vec<BatchInput> {
+    let mut inputs = Vec::new();
+    for spec in specs {
+        if let Some((_, member)) = spec.split_once(ZIP_MEMBER_SEP) {
+            inputs.push(BatchInput::new(PathBuf::from(spec), Path::new(member)));
+            continue;
+        }
+        let path = Path::new(spec);
+        if path.is_dir() {
+            let mut found = Vec::new();
+            walk_dir(path, &mut found);
+            if found.is_empty() {
+                panic!("{}: no DTA files found", spec);
+            }
+            found.sort();
+            for f in found {
+                let rel = f.strip_prefix(path).unwrap().to_path_buf();
+                inputs.push(BatchInput::new(f, &rel));
+            }
+        } else if is_pattern(spec) {
+            let base = pattern_base(spec);
+            let mut found: Vec<PathBuf> = glob::glob(spec)
+                .unwrap_or_else(|e| panic!("{}: {}", spec, e))
+                .map(|p| p.unwrap())
+                .filter(|p| p.is_file())
+                .collect();
+            if found.is_empty() {
+                panic!("{}: no files match", spec);
+            }
+            found.sort();
+            for f in found {
+                let rel = f.strip_prefix(&base).unwrap_or(&f).to_path_buf();
+                inputs.push(BatchInput::new(f, &rel));
+            }
+        } else {
+            let rel = PathBuf::from(path.file_name().unwrap_or(path.as_os_str()));
+            inputs.push(BatchInput::new(path.to_path_buf(), &rel));
+        }
+    }
+    inputs
+}
+
+/// Make the output path of `input` from `template`
+///
+/// The placeholders are:
+/// - `{stem}`: the file name without its `.dta` and compression extensions
+/// - `{name}`: the file name
+/// - `{relpath}`: the path relative to the directory or glob it was found
+///   under, without extensions
+/// - `{dir}`: the directory holding the file
+pub fn render_template(template: &str, input: &BatchInput) -> PathBuf {
+    let source = input.path.to_string_lossy();
+    let (dir, name) = match source.split_once(ZIP_MEMBER_SEP) {
+        Some((archive, member)) => {
+            let dir = Path::new(archive).parent().unwrap_or(Path::new(""));
+            let name = member.rsplit('/').next().unwrap();
+            (dir.to_path_buf(), name.to_string())
+        }
+        None => (
+            input.path.parent().unwrap_or(Path::new("")).to_path_buf(),
+            input.path.file_name().unwrap().to_string_lossy().into_owned(),
+        ),
+    };
+    let dir = if dir.as_os_str().is_empty() { PathBuf::from(".") } else { dir };
+    let stem = input.relpath.file_name().unwrap().to_string_lossy();
+    let out = template
+        .replace("{stem}", &stem)
+        .replace("{name}", &name)
+        .replace("{relpath}", &input.relpath.to_string_lossy())
+        .replace("{dir}", &dir.to_string_lossy());
+    PathBuf::from(out)
+}
+
+/// Whether `name` looks like a DTA file, possibly compressed
+fn is_dta_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let name = strip_compressed_ext(&name);
+    name.ends_with(".dta")
+}
+
+fn strip_compressed_ext(name: &str) -> &str {
+    match name.rsplit_once('.') {
+        Some((base, ext)) if COMPRESSED_EXTS.contains(&ext.to_ascii_lowercase().as_str()) => base,
+        _ => name,
+    }
+}
+
+/// Remove the compression extension, if any, and then one more
+/// extension, normally `.dta`
+fn strip_extensions(name: &str) -> String {
+    let name = strip_compressed_ext(name);
+    match name.rsplit_once('.') {
+        Some((base, _)) if !base.is_empty() => base.to_string(),
+        _ => name.to_string(),
+    }
+}
+
+fn walk_dir(dir: &Path, found: &mut Vec<PathBuf>) {
+    let entries = fs::read_dir(dir).unwrap_or_else(|e| panic!("{}: {}", dir.display(), e));
+    for entry in entries {
+        let entry = entry.unwrap();
+        let path = entry.path();
+        if path.is_dir() {
+            walk_dir(&path, found);
+        } else if is_dta_name(&entry.file_name().to_string_lossy()) {
+            found.push(path);
+        }
+    }
+}
+
+fn is_pattern(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+/// The leading directories of a glob pattern that hold no wildcards
+fn pattern_base(pattern: &str) -> PathBuf {
+    let mut base = PathBuf::new();
+    let components: Vec<_> = Path::new(pattern).components().collect();
+    //The last component names files, even without wildcards
+    for c in &components[..components.len() - 1] {
+        if is_pattern(&c.as_os_str().to_string_lossy()) {
+            break;
+        }
+        base.push(c);
+    }
+    base
+}
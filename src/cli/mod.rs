@@ -1,96 +1,617 @@
-use clap::Parser;
-use parquet::basic::{Compression,GzipLevel,ZstdLevel,BrotliLevel};
+use std::path::{Path, PathBuf};
 
-use nom::bytes::complete as nombc;
-use nom::character::complete as nomcc;
+use arrow::ipc::CompressionType;
+use clap::{Parser, Subcommand};
+use regex::Regex;
+use parquet::basic::{Compression, Encoding};
+use parquet::file::properties::{EnabledStatistics, WriterVersion};
+
+use dta2pqt::filter::Filter;
+use dta2pqt::input::InputOptions;
+use dta2pqt::output::{OutputFormat, OutputOptions};
+use dta2pqt::parquet::{parse_compression, parse_encoding, parse_statistics, parse_writer_version, ParquetOptions};
+use dta2pqt::select::{RowSelection, Sample};
+use dta2pqt::sort::SortOptions;
+use dta2pqt::spec::Spec;
+use dta2pqt::partition::PartitionOptions;
+use dta2pqt::split::SplitOptions;
+use dta2pqt::stata::dates::DateStyle;
+use dta2pqt::rename::{parse_replacement, NameTransform};
+use dta2pqt::translate::{VariableRule, Widening};
+use dta2pqt::text::{parse_label_style, LabelStyle, MissingStyle, Quoting, TextOptions};
 
-const DEFAULT_GZIP_LEVEL:i32 = 6;
-const DEFAULT_ZSTD_LEVEL:i32 = 3;
-const DEFAULT_BROTLI_LEVEL:i32 = 4;
 
 
 ///Convert Stata DTA file to parquet
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
-    ///The input DTA file
-    pub infile: std::path::PathBuf,
-    ///The output parquet file
-    pub outfile: std::path::PathBuf,
-    ///Default compression
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    ///The input DTA file, or - for standard input. It may be gzip or zstd
+    ///compressed, or a member of a zip archive given as archive.zip::path/in/zip.dta
+    #[arg(required = true)]
+    pub infile: Option<PathBuf>,
+    ///The output parquet file, or - for standard output
+    #[arg(required = true)]
+    pub outfile: Option<PathBuf>,
+    ///Parquet compression, as for --compression
     #[arg(value_parser = compression_parser)]
-    pub compression: Option<Compression>
+    pub compression: Option<Compression>,
+    #[command(flatten)]
+    pub opts: ConvertOptions,
 }
 
-fn compression_parser(s: &str) -> Result<Compression, &'static str> {
-    match p_compress(s){
-        Ok((_,c)) => Ok(c),
-        Err(_) => Err("Invalid compression parameter")
+#[derive(Subcommand)]
+pub enum Command {
+    ///Convert many DTA files
+    Batch(BatchArgs),
+    ///Append many DTA files into one output
+    Combine(CombineArgs),
+    ///Describe the variables, notes and characteristics of a DTA file
+    Describe(DescribeArgs),
+    ///Write a DDI 2.5 Codebook describing the variables of a DTA file
+    Ddi(DdiArgs),
+    ///Work with spec files
+    #[command(subcommand)]
+    Spec(SpecCommand),
+}
+
+#[derive(Subcommand)]
+pub enum SpecCommand {
+    ///Write a starter spec file for a DTA file, with an entry per variable
+    Init(SpecInitArgs),
+}
+
+#[derive(clap::Args)]
+pub struct SpecInitArgs {
+    ///The input DTA file
+    pub input: PathBuf,
+    ///The spec file to write. Defaults to standard output
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    ///Overwrite the spec file if it exists
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(clap::Args)]
+pub struct DescribeArgs {
+    ///The input DTA file
+    pub input: PathBuf,
+}
+
+#[derive(clap::Args)]
+pub struct DdiArgs {
+    ///The input DTA file
+    pub input: PathBuf,
+    ///The XML file to write. Defaults to standard output
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    ///Read the data for summary statistics of the variables
+    ///and the frequencies of their labelled values
+    #[arg(long)]
+    pub stats: bool,
+    ///Overwrite the output file if it exists
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(clap::Args)]
+pub struct BatchArgs {
+    ///Input DTA files, directories (searched for .dta files) or glob patterns
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+    ///Output path template. {stem} is replaced by the input file name without
+    ///extensions, {name} by the file name, {relpath} by the path below the
+    ///directory or glob the file was found under and {dir} by its directory
+    #[arg(short, long)]
+    pub output: String,
+    ///Number of files converted at the same time.
+    ///Defaults to a quarter of the threads
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+    ///Go on with the other files after a conversion fails
+    #[arg(short, long)]
+    pub keep_going: bool,
+    #[command(flatten)]
+    pub opts: ConvertOptions,
+}
+
+#[derive(clap::Args)]
+pub struct CombineArgs {
+    ///Input DTA files, directories (searched for .dta files) or glob patterns,
+    ///appended in this order. Variable types are widened to fit all files
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+    ///The output file, or - for standard output
+    #[arg(short, long)]
+    pub output: PathBuf,
+    ///Add a column holding the input file of each row, named source_file
+    ///unless a name is given
+    #[arg(long, value_name = "NAME", num_args = 0..=1, require_equals = true, default_missing_value = "source_file")]
+    pub source_column: Option<String>,
+    #[command(flatten)]
+    pub opts: ConvertOptions,
+}
+
+///Options shared by single, batch and combined conversions
+#[derive(clap::Args)]
+pub struct ConvertOptions {
+    ///Output format: parquet, ipc/feather (Arrow IPC file) or arrows (Arrow IPC stream).
+    ///Guessed from the output file extension if not given
+    #[arg(long, value_parser = format_parser)]
+    pub format: Option<OutputFormat>,
+    ///Buffer compression for Arrow IPC output: lz4 or zstd
+    #[arg(long, value_parser = ipc_compression_parser)]
+    pub ipc_compression: Option<CompressionType>,
+    ///Field delimiter for CSV output: a single character or "tab".
+    ///Defaults to "," or to tab for TSV
+    #[arg(long, value_parser = delimiter_parser)]
+    pub delimiter: Option<u8>,
+    ///When to quote fields in CSV output: necessary, always or never
+    #[arg(long, value_parser = quoting_parser, default_value = "necessary")]
+    pub quote: Quoting,
+    ///Do not write a header line in CSV output
+    #[arg(long)]
+    pub no_header: bool,
+    ///How to write dates in text output: iso, stata or raw
+    #[arg(long, value_parser = date_style_parser, default_value = "iso")]
+    pub dates: DateStyle,
+    ///Write labelled values in text output as code or text
+    #[arg(long, value_parser = parse_label_style, default_value = "code")]
+    pub value_labels: LabelStyle,
+    ///Also write the value label tables, a row per labelled value, to
+    ///OUT.labels.EXT, and the table of each variable to OUT.label_map.EXT
+    #[arg(long)]
+    pub label_tables: bool,
+    ///Also write a datapackage.json (Frictionless Table Schema) describing
+    ///the output, in its directory
+    #[arg(long)]
+    pub datapackage: bool,
+    ///How to write missing values in text output: empty, ".", ".a" (for
+    ///Stata's extended missing values) or any other token
+    #[arg(long, value_parser = missing_style_parser, default_value = "empty")]
+    pub missing_as: MissingStyle,
+    ///Overwrite the output file if it exists
+    #[arg(long)]
+    pub force: bool,
+    ///Decompress compressed input into a temporary file instead of memory
+    #[arg(long)]
+    pub spill: bool,
+    ///Directory for temporary files
+    #[arg(long)]
+    pub temp_dir: Option<PathBuf>,
+    ///Keep only the rows for which this Stata expression is true, as in
+    ///"year >= 2015 & !missing(income)". Supports comparisons, & | !,
+    ///+ - * /, missing(), inlist(), inrange() and value labels as "North":regionlbl
+    #[arg(long = "if", value_name = "EXPR", value_parser = Filter::parse)]
+    pub filter: Option<Filter>,
+    ///Convert only rows START..END, counted from 0 with END excluded.
+    ///Either end may be left out, as in 1000000..
+    #[arg(long, value_name = "START..END", value_parser = range_parser)]
+    pub rows: Option<(usize, Option<usize>)>,
+    ///Keep a random sample of about this fraction of the rows
+    #[arg(long, conflicts_with = "sample_n")]
+    pub sample_fraction: Option<f64>,
+    ///Keep a random sample of this many rows
+    #[arg(long)]
+    pub sample_n: Option<usize>,
+    ///Seed of the random sample. The same seed gives the same rows
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+    ///Write a directory tree with a subdirectory per value of these
+    ///columns, as in year=2020/state=CA/part-0.parquet
+    #[arg(long, value_delimiter = ',')]
+    pub partition_by: Vec<String>,
+    ///Name partition directories by value labels rather than codes
+    #[arg(long)]
+    pub partition_labels: bool,
+    ///Most partition files kept open at once
+    #[arg(long, default_value_t = 64)]
+    pub max_open_files: usize,
+    ///Split the output into files out-00000.parquet, out-00001.parquet and so
+    ///on of at most this many rows
+    #[arg(long)]
+    pub max_rows_per_file: Option<usize>,
+    ///Start a new output file once a file reaches this size. Takes a
+    ///number of bytes or a size such as 512M or 2G
+    #[arg(long, value_parser = size_parser)]
+    pub max_bytes_per_file: Option<u64>,
+    ///With split parquet output, also write a _metadata file holding the
+    ///footers of all the files
+    #[arg(long)]
+    pub summary_metadata: bool,
+    ///Check while converting that the rows are sorted as the file
+    ///claims, and warn if they are not
+    #[arg(long)]
+    pub verify_sort: bool,
+    ///Sort the output by these columns, with missing values last. Data
+    ///that does not fit in memory is sorted in temporary files
+    #[arg(long, value_delimiter = ',')]
+    pub sort_by: Vec<String>,
+    ///Memory used for sorting before spilling to temporary files,
+    ///as a number of bytes or a size such as 512M or 2G
+    #[arg(long, value_parser = size_parser, default_value = "1G")]
+    pub sort_memory: u64,
+    ///Read the data twice, first to find the narrowest type that holds the
+    ///values of each column: integers for integral numbers, float32 for
+    ///doubles that fit, and dictionaries for strings with few distinct values
+    #[arg(long)]
+    pub optimize: bool,
+    ///Write all integer columns as int64
+    #[arg(long)]
+    pub widen_integers: bool,
+    ///Write all float columns as float64
+    #[arg(long)]
+    pub widen_floats: bool,
+    ///Write all string columns as large strings, with 64-bit offsets
+    #[arg(long)]
+    pub large_strings: bool,
+    ///Name the columns after their variable labels, made lowercase with
+    ///characters other than letters and digits replaced by _. Variables
+    ///without a label keep their name
+    #[arg(long)]
+    pub names_from_labels: bool,
+    ///Longest column name made from a label. Defaults to 64
+    #[arg(long)]
+    pub max_name_length: Option<usize>,
+    ///Lowercase the column names
+    #[arg(long)]
+    pub lowercase: bool,
+    ///Put the column names in snake case, as household_id for HouseholdID
+    #[arg(long)]
+    pub snake_case: bool,
+    ///Put this before the column names
+    #[arg(long)]
+    pub prefix: Option<String>,
+    ///Put this after the column names
+    #[arg(long)]
+    pub suffix: Option<String>,
+    ///Replace the matches of a regular expression in the column names, as
+    ///PATTERN=REPLACEMENT where $1 and so on stand for the groups matched.
+    ///Applied in order before the other name settings. May be repeated
+    #[arg(long, value_name = "PATTERN=REPLACEMENT", value_parser = parse_replacement)]
+    pub rename_regex: Vec<(Regex, String)>,
+    ///Name a column, as VARIABLE=NAME, in place of the other name settings.
+    ///May be repeated
+    #[arg(long, value_name = "VARIABLE=NAME", value_parser = rename_parser)]
+    pub rename: Vec<(String, String)>,
+    ///Write these columns (or glob patterns) as decimals, with the number of
+    ///decimal places of their display format, as 2 for %12.2f
+    #[arg(long, value_delimiter = ',')]
+    pub decimal: Vec<String>,
+    ///Read settings from a TOML spec file: parquet settings, and how to
+    ///write each variable. Options given on the command line take precedence
+    #[arg(long)]
+    pub spec: Option<PathBuf>,
+    ///Parquet compression: uncompressed, snappy, gzip, lzo, brotli, lz4,
+    ///lz4_raw or zstd, with an optional level as in zstd(9). Defaults to snappy
+    #[arg(long = "compression", id = "parquet_compression", value_parser = compression_parser)]
+    pub parquet_compression: Option<Compression>,
+    ///Do not dictionary encode parquet columns
+    #[arg(long)]
+    pub no_dictionary: bool,
+    ///Target size of parquet data pages, as a number of bytes or a size such as 1M
+    #[arg(long, value_parser = size_parser)]
+    pub data_page_size: Option<u64>,
+    ///Rows in a parquet row group
+    #[arg(long)]
+    pub row_group_size: Option<usize>,
+    ///Parquet statistics to write: none, chunk or page
+    #[arg(long, value_parser = parse_statistics)]
+    pub statistics: Option<EnabledStatistics>,
+    ///Encode float columns with BYTE_STREAM_SPLIT rather than a dictionary
+    #[arg(long)]
+    pub byte_stream_split: bool,
+    ///Encode integer columns with DELTA_BINARY_PACKED rather than a dictionary
+    #[arg(long)]
+    pub delta_integers: bool,
+    ///Parquet writer version: 1 or 2
+    #[arg(long, value_parser = parse_writer_version)]
+    pub writer_version: Option<WriterVersion>,
+    ///Compression of a column, as COLUMN=CODEC. May be repeated
+    #[arg(long, value_name = "COLUMN=CODEC", value_parser = column_compression_parser)]
+    pub column_compression: Vec<(String, Compression)>,
+    ///Encoding of a column, as COLUMN=ENCODING with an encoding such as plain,
+    ///byte_stream_split or delta_binary_packed. May be repeated
+    #[arg(long, value_name = "COLUMN=ENCODING", value_parser = column_encoding_parser)]
+    pub column_encoding: Vec<(String, Encoding)>,
+    ///Whether to dictionary encode a column, as COLUMN=true or COLUMN=false.
+    ///May be repeated
+    #[arg(long, value_name = "COLUMN=BOOL", value_parser = column_dictionary_parser)]
+    pub column_dictionary: Vec<(String, bool)>,
+    ///Statistics of a column, as COLUMN=LEVEL. May be repeated
+    #[arg(long, value_name = "COLUMN=LEVEL", value_parser = column_statistics_parser)]
+    pub column_statistics: Vec<(String, EnabledStatistics)>,
+    ///Write parquet bloom filters for these columns, for fast lookups of values
+    #[arg(long, value_delimiter = ',')]
+    pub bloom_filter: Vec<String>,
+    ///False positive probability of the bloom filters. Defaults to 0.05
+    #[arg(long)]
+    pub bloom_filter_fpp: Option<f64>,
+    ///Distinct values in a row group of a column, as COLUMN=N, to size its bloom
+    ///filter. Counted in each row group if not given. May be repeated
+    #[arg(long, value_name = "COLUMN=N", value_parser = column_ndv_parser)]
+    pub bloom_filter_ndv: Vec<(String, u64)>,
+    ///Number of threads to use. Defaults to the number of CPUs
+    #[arg(long)]
+    pub threads: Option<usize>,
+}
+
+impl ConvertOptions {
+    pub fn output_options(&self, out_path: &Path) -> OutputOptions {
+        let format = self.format.unwrap_or_else(|| OutputFormat::from_path(out_path));
+        let spec = self.spec.as_deref().map(Spec::load).unwrap_or_default();
+        OutputOptions {
+            format,
+            ipc_compression: self.ipc_compression,
+            text: TextOptions {
+                delimiter: self.delimiter.unwrap_or(if format == OutputFormat::Tsv { b'\t' } else { b',' }),
+                quoting: self.quote,
+                header: !self.no_header,
+                dates: self.dates,
+                labels: self.value_labels,
+                missing: self.missing_as.clone(),
+            },
+            force: self.force,
+            partition: if self.partition_by.is_empty() {
+                None
+            } else {
+                Some(PartitionOptions {
+                    columns: self.partition_by.clone(),
+                    labels: self.partition_labels,
+                    max_open: self.max_open_files,
+                })
+            },
+            split: if self.max_rows_per_file.is_none() && self.max_bytes_per_file.is_none() {
+                None
+            } else {
+                Some(SplitOptions {
+                    max_rows: self.max_rows_per_file.map(|n| n.max(1)),
+                    max_bytes: self.max_bytes_per_file,
+                    summary: self.summary_metadata,
+                })
+            },
+            verify_sort: self.verify_sort,
+            optimize: self.optimize,
+            label_tables: self.label_tables,
+            datapackage: self.datapackage,
+            parquet: self.parquet_options(&spec),
+            variables: self.variable_rules(&spec),
+            names: self.name_transform(&spec),
+            widening: Widening {
+                integers: self.widen_integers,
+                floats: self.widen_floats,
+                strings: self.large_strings,
+            },
+            sort: if self.sort_by.is_empty() {
+                None
+            } else {
+                Some(SortOptions {
+                    columns: self.sort_by.clone(),
+                    memory: self.sort_memory as usize,
+                    temp_dir: self.temp_dir.clone(),
+                })
+            },
+        }
+    }
+
+    /// The naming settings of the spec file, with those of
+    /// the command line added
+    pub fn name_transform(&self, spec: &Spec) -> NameTransform {
+        let mut n = spec.name_transform();
+        n.from_labels |= self.names_from_labels;
+        if let Some(l) = self.max_name_length {
+            n.max_length = l;
+        }
+        n.lowercase |= self.lowercase;
+        n.snake_case |= self.snake_case;
+        n.replace.extend(self.rename_regex.iter().cloned());
+        if let Some(p) = &self.prefix {
+            n.prefix = p.clone();
+        }
+        if let Some(s) = &self.suffix {
+            n.suffix = s.clone();
+        }
+        n.map.extend(self.rename.iter().cloned());
+        n
+    }
+
+    /// The variable rules of the spec file, followed by those
+    /// of the command line
+    pub fn variable_rules(&self, spec: &Spec) -> Vec<VariableRule> {
+        let mut rules = spec.variable_rules();
+        for p in &self.decimal {
+            rules.push(VariableRule {
+                pattern: glob::Pattern::new(p).unwrap_or_else(|e| panic!("--decimal {}: {}", p, e)),
+                decimal: true,
+                ..Default::default()
+            });
+        }
+        rules
+    }
+
+    /// The parquet settings of the spec file, overridden by
+    /// those on the command line
+    pub fn parquet_options(&self, spec: &Spec) -> ParquetOptions {
+        let mut p = spec.parquet_options();
+        if let Some(c) = self.parquet_compression {
+            p.compression = c;
+        }
+        if self.no_dictionary {
+            p.dictionary = false;
+        }
+        if let Some(n) = self.data_page_size {
+            p.data_page_size = Some(n as usize);
+        }
+        if let Some(n) = self.row_group_size {
+            p.row_group_size = Some(n);
+        }
+        if let Some(s) = self.statistics {
+            p.statistics = Some(s);
+        }
+        p.byte_stream_split |= self.byte_stream_split;
+        p.delta_integers |= self.delta_integers;
+        if let Some(v) = self.writer_version {
+            p.writer_version = Some(v);
+        }
+        for (c, x) in &self.column_compression {
+            p.columns.entry(c.clone()).or_default().compression = Some(*x);
+        }
+        for (c, x) in &self.column_encoding {
+            p.columns.entry(c.clone()).or_default().encoding = Some(*x);
+        }
+        for (c, x) in &self.column_dictionary {
+            p.columns.entry(c.clone()).or_default().dictionary = Some(*x);
+        }
+        for (c, x) in &self.column_statistics {
+            p.columns.entry(c.clone()).or_default().statistics = Some(*x);
+        }
+        if let Some(fpp) = self.bloom_filter_fpp {
+            p.bloom_filter_fpp = Some(fpp);
+        }
+        for c in &self.bloom_filter {
+            p.columns.entry(c.clone()).or_default().bloom_filter = true;
+        }
+        for (c, x) in &self.bloom_filter_ndv {
+            p.columns.entry(c.clone()).or_default().bloom_filter_ndv = Some(*x);
+        }
+        p
+    }
+
+    pub fn row_selection(&self) -> RowSelection {
+        let (start, end) = self.rows.unwrap_or((0, None));
+        RowSelection {
+            start,
+            end,
+            sample: match (self.sample_fraction, self.sample_n) {
+                (Some(f), _) => Some(Sample::Fraction(f)),
+                (_, Some(n)) => Some(Sample::Count(n)),
+                _ => None,
+            },
+            seed: self.seed,
+            filter: self.filter.clone(),
+        }
+    }
+
+    pub fn input_options(&self) -> InputOptions {
+        InputOptions {
+            spill: self.spill,
+            temp_dir: self.temp_dir.clone(),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        match self.threads {
+            Some(n) => n.max(1),
+            None => usize::from(std::thread::available_parallelism().unwrap()),
+        }
     }
 }
 
-fn p_compress(s: &str) -> nom::IResult<&str,Compression> {
-    let (s,c) = nom::branch::alt((
-        p_snappy,p_lzo,p_lz4,p_lz4_raw,
-        p_gzip,p_zstd,p_brotli
-    ))(s)?;
-    let (s,_) = nom::combinator::eof(s)?;
-    Ok((s,c))
+fn format_parser(s: &str) -> Result<OutputFormat, &'static str> {
+    OutputFormat::from_name(s).ok_or("Invalid output format")
 }
- 
-fn p_snappy(s: &str) -> nom::IResult<&str,Compression> {
-    let (s,_) = nombc::tag_no_case("snappy")(s)?;
-    Ok((s,Compression::SNAPPY))
+
+fn delimiter_parser(s: &str) -> Result<u8, &'static str> {
+    match s {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ if s.len() == 1 => Ok(s.as_bytes()[0]),
+        _ => Err("Delimiter must be a single character")
+    }
 }
 
-fn p_lzo(s: &str) -> nom::IResult<&str,Compression> {
-    let (s,_) = nombc::tag_no_case("lzo")(s)?;
-    Ok((s,Compression::LZO))
+fn quoting_parser(s: &str) -> Result<Quoting, &'static str> {
+    match s.to_ascii_lowercase().as_str() {
+        "necessary" => Ok(Quoting::Necessary),
+        "always" => Ok(Quoting::Always),
+        "never" => Ok(Quoting::Never),
+        _ => Err("Invalid quoting parameter")
+    }
 }
 
-fn p_lz4(s: &str) -> nom::IResult<&str,Compression> {
-    let (s,_) = nombc::tag_no_case("lz4")(s)?;
-    Ok((s,Compression::LZ4))
+fn date_style_parser(s: &str) -> Result<DateStyle, &'static str> {
+    match s.to_ascii_lowercase().as_str() {
+        "iso" => Ok(DateStyle::Iso),
+        "stata" => Ok(DateStyle::Stata),
+        "raw" => Ok(DateStyle::Raw),
+        _ => Err("Invalid date style")
+    }
 }
 
-fn p_lz4_raw(s: &str) -> nom::IResult<&str,Compression> {
-    let (s,_) = nombc::tag_no_case("lz4_raw")(s)?;
-    Ok((s,Compression::LZ4_RAW))
+fn missing_style_parser(s: &str) -> Result<MissingStyle, &'static str> {
+    Ok(match s {
+        "empty" | "" => MissingStyle::Empty,
+        "." => MissingStyle::Dot,
+        ".a" => MissingStyle::Stata,
+        _ => MissingStyle::Token(s.to_string())
+    })
 }
 
-fn p_gzip(s: &str) -> nom::IResult<&str,Compression> {
-    let (s,_) = nombc::tag_no_case("gzip")(s)?;
-    let (s,lvl) = p_optno(s,DEFAULT_GZIP_LEVEL)?;
-    let lvl = match GzipLevel::try_new(lvl.try_into().unwrap()) {
-        Ok(l) => l,
-        Err(_) => panic!("Invalid gzip level")
-    };
-    Ok((s,Compression::GZIP(lvl)))
+fn range_parser(s: &str) -> Result<(usize, Option<usize>), &'static str> {
+    let (a, b) = s.split_once("..").ok_or("Row range must be START..END")?;
+    let start = if a.is_empty() { 0 } else { a.parse().map_err(|_| "Invalid row range start")? };
+    let end = if b.is_empty() { None } else { Some(b.parse().map_err(|_| "Invalid row range end")?) };
+    Ok((start, end))
 }
 
-fn p_zstd(s: &str) -> nom::IResult<&str,Compression> {
-    let (s,_) = nombc::tag_no_case("zstd")(s)?;
-    let (s,lvl) = p_optno(s,DEFAULT_ZSTD_LEVEL)?;
-    let lvl = match ZstdLevel::try_new(lvl) {
-        Ok(l) => l,
-        Err(_) => panic!("Invalid zstd level")
+fn size_parser(s: &str) -> Result<u64, &'static str> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
     };
-    Ok((s,Compression::ZSTD(lvl)))
+    let n: u64 = num.parse().map_err(|_| "Invalid size")?;
+    let mult: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err("Invalid size unit"),
+    };
+    n.checked_mul(mult).ok_or("Size too large")
 }
 
-fn p_brotli(s: &str) -> nom::IResult<&str,Compression> {
-    let (s,_) = nombc::tag_no_case("brotli")(s)?;
-    let (s,lvl) = p_optno(s,DEFAULT_BROTLI_LEVEL)?;
-    let lvl = match BrotliLevel::try_new(lvl.try_into().unwrap()) {
-        Ok(l) => l,
-        Err(_) => panic!("Invalid brotli level")
-    };
-    Ok((s,Compression::BROTLI(lvl)))
+fn ipc_compression_parser(s: &str) -> Result<CompressionType, &'static str> {
+    match s.to_ascii_lowercase().as_str() {
+        "lz4" | "lz4_frame" => Ok(CompressionType::LZ4_FRAME),
+        "zstd" => Ok(CompressionType::ZSTD),
+        _ => Err("Invalid IPC compression parameter")
+    }
 }
 
-fn p_optno(s:&str,n:i32) -> nom::IResult<&str,i32> {
-    nom::branch::alt((
-        nom::sequence::delimited(nombc::tag("("),nomcc::i32,nombc::tag(")")),
-        nom::combinator::value(n,nom::combinator::eof)
-    ))(s)
+fn compression_parser(s: &str) -> Result<Compression, &'static str> {
+    parse_compression(s)
+}
+
+fn column_setting<T>(s: &str, parse: fn(&str) -> Result<T, &'static str>) -> Result<(String, T), &'static str> {
+    let (column, value) = s.split_once('=').ok_or("Column setting must be COLUMN=VALUE")?;
+    Ok((column.to_string(), parse(value)?))
+}
+
+fn rename_parser(s: &str) -> Result<(String, String), &'static str> {
+    let (var, name) = s.split_once('=').ok_or("Rename must be VARIABLE=NAME")?;
+    Ok((var.to_string(), name.to_string()))
+}
+
+fn column_compression_parser(s: &str) -> Result<(String, Compression), &'static str> {
+    column_setting(s, parse_compression)
 }
 
+fn column_encoding_parser(s: &str) -> Result<(String, Encoding), &'static str> {
+    column_setting(s, parse_encoding)
+}
+
+fn column_dictionary_parser(s: &str) -> Result<(String, bool), &'static str> {
+    column_setting(s, |v| v.parse().map_err(|_| "Dictionary setting must be true or false"))
+}
+
+fn column_ndv_parser(s: &str) -> Result<(String, u64), &'static str> {
+    column_setting(s, |v| v.parse().map_err(|_| "Invalid number of distinct values"))
+}
+
+fn column_statistics_parser(s: &str) -> Result<(String, EnabledStatistics), &'static str> {
+    column_setting(s, parse_statistics)
+}
@@ -0,0 +1,125 @@
+use std::cmp::max;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use arrow::array::{new_null_array, ArrayRef, StringArray};
+use arrow::compute::cast;
+use arrow::datatypes::SchemaRef;
+use arrow_array::RecordBatch;
+
+use super::output::BatchSink;
+use super::stata::{Var, VarType};
+
+/// The smallest type holding the values of both `a` and `b`, or
+/// `None` if one is numeric and the other a string
+///
+/// Integers widen to larger integers, and to float if mixed with
+/// float, except `long`, which only fits exactly in a double.
+/// Strings widen to the longer `str`, and to `strL` if either is one.
+pub fn unify_types(a: VarType, b: VarType) -> Option<VarType> {
+    use VarType::*;
+    let as_strf = |t| match t {
+        TASCII(n) => TStrf(n as u16),
+        t => t,
+    };
+    match (as_strf(a), as_strf(b)) {
+        (TStrf(m), TStrf(n)) => Some(TStrf(max(m, n))),
+        (TStrl, TStrf(_) | TStrl) | (TStrf(_), TStrl) => Some(TStrl),
+        (TStrf(_) | TStrl, _) | (_, TStrf(_) | TStrl) => None,
+        (TDouble, _) | (_, TDouble) => Some(TDouble),
+        (TFloat, TLong) | (TLong, TFloat) => Some(TDouble),
+        (TFloat, _) | (_, TFloat) => Some(TFloat),
+        (TLong, _) | (_, TLong) => Some(TLong),
+        (TInt, _) | (_, TInt) => Some(TInt),
+        _ => Some(TByte),
+    }
+}
+
+/// The variables of files to be appended
+///
+/// Variables are taken in the order they first appear in, with their
+/// types widened by `unify_types` to hold the values of all files.
+/// Formats and labels are those of the first file with the variable.
+pub fn unify_vars(files: &[(PathBuf, Vec<Var>)]) -> Vec<Var> {
+    let mut vars: Vec<Var> = Vec::new();
+    let mut first_seen: Vec<&PathBuf> = Vec::new();
+    for (path, file_vars) in files {
+        for v in file_vars {
+            match vars.iter().position(|u| u.name == v.name) {
+                Some(i) => {
+                    let u = &mut vars[i];
+                    u.ty = unify_types(u.ty, v.ty).unwrap_or_else(|| {
+                        panic!(
+                            "{} is {:?} in {} but {:?} in {}",
+                            v.name,
+                            u.ty,
+                            first_seen[i].display(),
+                            v.ty,
+                            path.display()
+                        )
+                    });
+                    if u.dictionary.is_none() {
+                        u.dictionary = v.dictionary.clone();
+                    }
+                }
+                None => {
+                    vars.push(v.clone());
+                    first_seen.push(path);
+                }
+            }
+        }
+    }
+    vars
+}
+
+/// Make `batch` fit `schema`, matching columns by name
+///
+/// Columns are cast to the type of the schema, and columns of the
+/// schema that `batch` lacks are filled with nulls.
+pub fn conform_batch(batch: &RecordBatch, schema: &SchemaRef) -> RecordBatch {
+    let n = batch.num_rows();
+    let columns: Vec<ArrayRef> = schema
+        .fields()
+        .iter()
+        .map(|f| match batch.column_by_name(f.name()) {
+            Some(c) if c.data_type() == f.data_type() => c.clone(),
+            Some(c) => cast(c, f.data_type()).unwrap(),
+            None => new_null_array(f.data_type(), n),
+        })
+        .collect();
+    RecordBatch::try_new(schema.clone(), columns).unwrap()
+}
+
+/// Appends the batches of one file to a sink shared by several files
+///
+/// Batches are conformed to the schema of the shared sink. Finishing
+/// this sink leaves the shared one open for the next file.
+pub struct AppendSink<'a> {
+    inner: &'a mut dyn BatchSink,
+    schema: SchemaRef,
+    /// Index of the source file column and its value
+    source: Option<(usize, String)>,
+}
+
+impl<'a> AppendSink<'a> {
+    /// `source` gives the name of a column of `schema` to fill
+    /// with the name of the file
+    pub fn new(inner: &'a mut dyn BatchSink, schema: SchemaRef, source: Option<(&str, String)>) -> AppendSink<'a> {
+        let source = source.map(|(column, file)| (schema.index_of(column).unwrap(), file));
+        AppendSink { inner, schema, source }
+    }
+}
+
+impl BatchSink for AppendSink<'_> {
+    fn write(&mut self, batch: &RecordBatch) {
+        let mut batch = conform_batch(batch, &self.schema);
+        if let Some((i, file)) = &self.source {
+            let mut columns = batch.columns().to_vec();
+            columns[*i] = Arc::new(StringArray::from(vec![file.as_str(); batch.num_rows()]));
+            batch = RecordBatch::try_new(self.schema.clone(), columns).unwrap();
+        }
+        self.inner.write(&batch);
+    }
+
+    fn finish(self: Box<Self>) {}
+}
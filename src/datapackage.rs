@@ -0,0 +1,368 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use arrow::array::{Array, AsArray};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Float64Type, Schema};
+use arrow_array::RecordBatch;
+use serde::Serialize;
+
+use super::describe::notes;
+use super::output::{check_overwrite, write_file, BatchSink, OutputFormat, OutputOptions, STDOUT};
+use super::split::existing_parts;
+use super::stata::dates::{DateKind, DateStyle};
+use super::stata::file::Metadata;
+use super::stata::ValueLabelTable;
+use super::text::{LabelStyle, MissingStyle};
+use super::translate::{Translation, MISSING_CODE_OF};
+
+/// File name of the descriptor, written in the directory of the output
+pub const DESCRIPTOR: &str = "datapackage.json";
+
+/// A Frictionless Data Package describing the output of a conversion,
+/// with a single tabular resource
+#[derive(Debug, Serialize)]
+pub struct DataPackage {
+    profile: &'static str,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    resources: Vec<Resource>,
+    /// The labelled columns, to be given an `enum` constraint if
+    /// all their values turn out to be labelled
+    #[serde(skip)]
+    labelled: Vec<LabelledColumn>,
+}
+
+/// A labelled column, and whether all its values seen so far are
+/// among the values of its labels, or among the labels when written
+/// as labels
+#[derive(Debug)]
+struct LabelledColumn {
+    /// Index of the column in the batches written
+    column: usize,
+    /// Index of the field in the schema of the package
+    field: usize,
+    values: HashSet<i32>,
+    labels: HashSet<String>,
+    as_labels: bool,
+    covered: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Resource {
+    profile: &'static str,
+    name: String,
+    path: ResourcePath,
+    format: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dialect: Option<Dialect>,
+    schema: TableSchema,
+}
+
+/// A file, or the files of split or partitioned output
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum ResourcePath {
+    File(String),
+    Parts(Vec<String>),
+}
+
+#[derive(Debug, Serialize)]
+struct Dialect {
+    delimiter: String,
+    header: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TableSchema {
+    fields: Vec<FieldDescriptor>,
+    missing_values: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct FieldDescriptor {
+    name: String,
+    #[serde(rename = "type")]
+    field_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    constraints: Option<Constraints>,
+    /// The labels of the codes of a labelled column
+    #[serde(skip_serializing_if = "Option::is_none")]
+    categories: Option<Vec<Category>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Constraints {
+    #[serde(rename = "enum")]
+    values: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+struct Category {
+    value: i32,
+    label: String,
+}
+
+impl DataPackage {
+    /// The package of the output `out_path` of the file `md`, written
+    /// with the columns of `schema` as `translation` makes them. The
+    /// resource path is set by `write` once the output is complete.
+    pub fn new(out_path: &Path, md: &Metadata, translation: &Translation, schema: &Schema, opts: &OutputOptions) -> DataPackage {
+        let stem = if opts.partition.is_some() { out_path.file_name() } else { out_path.file_stem() };
+        let name = package_name(&stem.unwrap().to_string_lossy());
+        let text = opts.format.is_text();
+        let mut fields = Vec::new();
+        let mut labelled = Vec::new();
+        for (k, f) in schema.fields().iter().enumerate() {
+            //Text output renders missing codes in the column they belong to
+            if text && f.metadata().contains_key(MISSING_CODE_OF) {
+                continue;
+            }
+            let Some(&i) = translation.sources().get(k) else {
+                fields.push(FieldDescriptor::plain(f.name(), f.data_type()));
+                continue;
+            };
+            let (v, out) = (&md.vars[i], &translation.out_vars()[k]);
+            let mut field = FieldDescriptor::plain(f.name(), f.data_type());
+            field.description = Some(v.var_label.clone()).filter(|l| !l.is_empty());
+            if text && opts.text.dates != DateStyle::Raw {
+                if let Some(kind) = DateKind::from_format(&out.format) {
+                    (field.field_type, field.format) = (text_date_type(kind, opts.text.dates), None);
+                }
+            }
+            //Labels are written by the translation, or by text output.
+            //Values without a label are written as they are, so the enum
+            //constraint is only known to hold once all rows are seen.
+            let as_labels = (out.dictionary.is_none() && is_string(f.data_type()))
+                || (text && opts.text.labels == LabelStyle::Text && out.dictionary.is_some());
+            if let Some(t) = &v.dictionary {
+                if as_labels {
+                    field.field_type = "string";
+                } else if out.dictionary.is_some() {
+                    field.categories = Some(categories(t));
+                }
+                if as_labels || out.dictionary.is_some() {
+                    labelled.push(LabelledColumn {
+                        column: k,
+                        field: fields.len(),
+                        values: t.values.iter().copied().collect(),
+                        labels: t.labels.iter().cloned().collect(),
+                        as_labels,
+                        covered: true,
+                    });
+                }
+            }
+            fields.push(field);
+        }
+        let dialect = matches!(opts.format, OutputFormat::Csv | OutputFormat::Tsv).then(|| Dialect {
+            delimiter: (opts.text.delimiter as char).to_string(),
+            header: opts.text.header,
+        });
+        let dta_notes = md.characteristics.get("_dta").map(notes).unwrap_or_default();
+        DataPackage {
+            profile: "tabular-data-package",
+            name: name.clone(),
+            title: Some(md.data_label.clone()).filter(|l| !l.is_empty()),
+            description: Some(dta_notes.join("\n")).filter(|d| !d.is_empty()),
+            resources: vec![Resource {
+                profile: "tabular-data-resource",
+                name,
+                path: ResourcePath::Parts(Vec::new()),
+                format: opts.format.extension(),
+                dialect,
+                schema: TableSchema { fields, missing_values: missing_values(opts) },
+            }],
+            labelled,
+        }
+    }
+
+    /// Look for values without a label in the labelled columns of
+    /// `batch`, a batch of the output
+    fn scan(&mut self, batch: &RecordBatch) {
+        for l in self.labelled.iter_mut().filter(|l| l.covered) {
+            let a = batch.column(l.column);
+            l.covered = if is_string(a.data_type()) {
+                let a = cast(a, &DataType::Utf8).unwrap();
+                a.as_string::<i32>().iter().flatten().all(|s| l.labels.contains(s))
+            } else {
+                let a = cast(a, &DataType::Float64).unwrap();
+                a.as_primitive::<Float64Type>()
+                    .iter()
+                    .flatten()
+                    .all(|x| x.fract() == 0.0 && i32::try_from(x as i64).is_ok_and(|x| l.values.contains(&x)))
+            };
+        }
+    }
+
+    /// Write the package next to the complete output `out_path`,
+    /// listing the files it was written to
+    pub fn write(mut self, out_path: &Path, opts: &OutputOptions) {
+        for l in self.labelled.iter().filter(|l| l.covered) {
+            let mut values: Vec<i32> = l.values.iter().copied().collect();
+            values.sort_unstable();
+            let values = if l.as_labels {
+                let mut labels: Vec<&str> = l.labels.iter().map(|s| s.as_str()).collect();
+                labels.sort();
+                labels.into()
+            } else {
+                values.into()
+            };
+            self.resources[0].schema.fields[l.field].constraints = Some(Constraints { values });
+        }
+        let path = descriptor_path(out_path);
+        let dir = path.parent().unwrap();
+        let mut parts: Vec<String> = output_files(out_path, opts)
+            .iter()
+            .map(|p| p.strip_prefix(dir).unwrap_or(p).to_string_lossy().replace('\\', "/"))
+            .collect();
+        parts.sort();
+        self.resources[0].path = if parts.len() == 1 && opts.partition.is_none() {
+            ResourcePath::File(parts.remove(0))
+        } else {
+            ResourcePath::Parts(parts)
+        };
+        let json = serde_json::to_string_pretty(&self).unwrap();
+        write_file(&path, (json + "\n").as_bytes(), opts.force);
+    }
+}
+
+impl FieldDescriptor {
+    fn plain(name: &str, t: &DataType) -> FieldDescriptor {
+        let (field_type, format) = field_type(t);
+        FieldDescriptor {
+            name: name.to_string(),
+            field_type,
+            format,
+            description: None,
+            constraints: None,
+            categories: None,
+        }
+    }
+}
+
+/// The path of the descriptor of the output `out_path`
+pub fn descriptor_path(out_path: &Path) -> PathBuf {
+    if out_path.as_os_str() == STDOUT {
+        panic!("A data package cannot be written with the output to standard output");
+    }
+    out_path.with_file_name(DESCRIPTOR)
+}
+
+/// Panic before converting if the descriptor of `out_path` exists and
+/// may not be overwritten
+pub fn check_descriptor(out_path: &Path, force: bool) {
+    check_overwrite(&descriptor_path(out_path), force);
+}
+
+/// The files of the output `out_path`: the parts of split output, the
+/// files below the directory of partitioned output, or `out_path`
+fn output_files(out_path: &Path, opts: &OutputOptions) -> Vec<PathBuf> {
+    if opts.partition.is_some() {
+        let escaped = glob::Pattern::escape(&out_path.to_string_lossy());
+        let pattern = format!("{}/**/*.{}", escaped, opts.format.extension());
+        glob::glob(&pattern).unwrap().map(|p| p.unwrap()).collect()
+    } else if opts.split.is_some() {
+        existing_parts(out_path)
+    } else {
+        vec![out_path.to_path_buf()]
+    }
+}
+
+/// A package name as the spec allows: lowercase letters, digits,
+/// `-`, `.` and `_`
+fn package_name(stem: &str) -> String {
+    stem.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-._".contains(c) { c.to_ascii_lowercase() } else { '-' })
+        .collect()
+}
+
+fn field_type(t: &DataType) -> (&'static str, Option<&'static str>) {
+    match t {
+        DataType::Boolean => ("boolean", None),
+        t if t.is_integer() => ("integer", None),
+        t if t.is_floating() => ("number", None),
+        DataType::Decimal128(_, _) | DataType::Decimal256(_, _) => ("number", None),
+        DataType::Utf8 | DataType::LargeUtf8 => ("string", None),
+        DataType::Dictionary(_, values) => field_type(values),
+        DataType::Date32 | DataType::Date64 => ("date", None),
+        DataType::Timestamp(_, _) => ("datetime", None),
+        DataType::Binary | DataType::LargeBinary => ("string", Some("binary")),
+        _ => ("any", None),
+    }
+}
+
+fn is_string(t: &DataType) -> bool {
+    field_type(t).0 == "string"
+}
+
+/// The type of a date variable as text output renders it
+fn text_date_type(kind: DateKind, style: DateStyle) -> &'static str {
+    match (kind, style) {
+        (DateKind::Year, _) => "year",
+        (DateKind::Day, DateStyle::Iso) => "date",
+        (DateKind::Millis, DateStyle::Iso) => "datetime",
+        (DateKind::Month, DateStyle::Iso) => "yearmonth",
+        _ => "string",
+    }
+}
+
+fn categories(t: &ValueLabelTable) -> Vec<Category> {
+    t.values
+        .iter()
+        .zip(&t.labels)
+        .map(|(&value, label)| Category { value, label: label.clone() })
+        .collect()
+}
+
+/// How missing values appear in the output: as nulls, read as empty
+/// strings, except in text output written with a missing value style
+fn missing_values(opts: &OutputOptions) -> Vec<String> {
+    if !opts.format.is_text() {
+        return vec![String::new()];
+    }
+    match &opts.text.missing {
+        MissingStyle::Empty => vec![String::new()],
+        MissingStyle::Dot => vec![".".to_string()],
+        MissingStyle::Stata => {
+            let extended = ('a'..='z').map(|c| format!(".{}", c));
+            [String::new(), ".".to_string()].into_iter().chain(extended).collect()
+        }
+        MissingStyle::Token(t) => vec![t.clone()],
+    }
+}
+
+/// Passes batches on to `inner`, checking the labelled columns of
+/// the package against the value labels
+pub struct LabelScanSink<'a> {
+    inner: Box<dyn BatchSink + 'a>,
+    package: &'a mut DataPackage,
+}
+
+impl<'a> LabelScanSink<'a> {
+    pub fn new(inner: Box<dyn BatchSink + 'a>, package: &'a mut DataPackage) -> LabelScanSink<'a> {
+        LabelScanSink { inner, package }
+    }
+}
+
+impl BatchSink for LabelScanSink<'_> {
+    fn write(&mut self, batch: &RecordBatch) {
+        self.package.scan(batch);
+        self.inner.write(batch);
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.inner.bytes_written()
+    }
+
+    fn finish(self: Box<Self>) {
+        self.inner.finish();
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use arrow::array::{Array, ArrayRef, AsArray};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Float64Type};
+use arrow_array::RecordBatch;
+use rayon::prelude::*;
+
+use super::describe::notes;
+use super::stata::dates::DateKind;
+use super::stata::file::Metadata;
+use super::stata::{Var, VarType};
+
+/// Value label values standing for `.`, after which come `.a` to `.z`
+const MISSING_LABEL_VALUE: i32 = 2147483621;
+
+/// Summary statistics of the variables of a file, gathered from batches
+/// laid out by `make_schema_with_missing_codes`
+#[derive(Debug, Clone)]
+pub struct CodebookStats {
+    vars: Vec<VarStats>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct VarStats {
+    valid: usize,
+    invalid: usize,
+    min: Option<f64>,
+    max: Option<f64>,
+    /// Running mean of the values and sum of their squared deviations
+    /// from it, by Welford's method
+    mean: f64,
+    m2: f64,
+    /// Counts of the integral values of numeric variables with value labels
+    freq: BTreeMap<i64, usize>,
+    /// Counts of `.` and `.a` to `.z`
+    missing: BTreeMap<String, usize>,
+}
+
+impl CodebookStats {
+    pub fn new(md: &Metadata) -> CodebookStats {
+        CodebookStats { vars: vec![VarStats::default(); md.nvars] }
+    }
+
+    pub fn update(&mut self, md: &Metadata, batch: &RecordBatch) {
+        //Missing code columns follow the variables, one per numeric variable
+        let mut codes = md.nvars;
+        let columns: Vec<(ArrayRef, Option<ArrayRef>)> = md
+            .vars
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let code = is_numeric(v).then(|| {
+                    codes += 1;
+                    batch.column(codes - 1).clone()
+                });
+                (batch.column(i).clone(), code)
+            })
+            .collect();
+        self.vars
+            .par_iter_mut()
+            .zip(&md.vars)
+            .zip(columns)
+            .for_each(|((s, v), (values, codes))| s.update(v, &values, codes.as_ref()));
+    }
+
+    /// Combine the statistics of two parts of a file
+    pub fn merge(mut self, other: CodebookStats) -> CodebookStats {
+        for (a, b) in self.vars.iter_mut().zip(other.vars) {
+            //The means and squared deviations combined as by Chan et al.
+            if b.valid > 0 {
+                let (na, nb) = (a.valid as f64, b.valid as f64);
+                let delta = b.mean - a.mean;
+                a.mean += delta * nb / (na + nb);
+                a.m2 += b.m2 + delta * delta * na * nb / (na + nb);
+            }
+            a.valid += b.valid;
+            a.invalid += b.invalid;
+            a.min = [a.min, b.min].into_iter().flatten().reduce(f64::min);
+            a.max = [a.max, b.max].into_iter().flatten().reduce(f64::max);
+            for (k, n) in b.freq {
+                *a.freq.entry(k).or_default() += n;
+            }
+            for (k, n) in b.missing {
+                *a.missing.entry(k).or_default() += n;
+            }
+        }
+        self
+    }
+}
+
+impl VarStats {
+    fn update(&mut self, v: &Var, values: &ArrayRef, codes: Option<&ArrayRef>) {
+        match values.data_type() {
+            DataType::Utf8 => {
+                for s in values.as_string::<i32>().iter() {
+                    self.count_string(s.is_some_and(|s| !s.is_empty()));
+                }
+            }
+            DataType::Binary => {
+                for s in values.as_binary::<i32>().iter() {
+                    self.count_string(s.is_some_and(|s| !s.is_empty()));
+                }
+            }
+            _ => {
+                let x = cast(values, &DataType::Float64).unwrap();
+                for x in x.as_primitive::<Float64Type>().iter().flatten() {
+                    self.valid += 1;
+                    self.min = Some(self.min.map_or(x, |m| m.min(x)));
+                    self.max = Some(self.max.map_or(x, |m| m.max(x)));
+                    let delta = x - self.mean;
+                    self.mean += delta / self.valid as f64;
+                    self.m2 += delta * (x - self.mean);
+                    if v.dictionary.is_some() && x.fract() == 0.0 {
+                        *self.freq.entry(x as i64).or_default() += 1;
+                    }
+                }
+                self.invalid += values.null_count();
+                if let Some(codes) = codes {
+                    for c in codes.as_string::<i32>().iter().flatten() {
+                        *self.missing.entry(c.to_string()).or_default() += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn count_string(&mut self, valid: bool) {
+        if valid {
+            self.valid += 1;
+        } else {
+            self.invalid += 1;
+        }
+    }
+
+    fn mean(&self) -> Option<f64> {
+        (self.valid > 0 && self.min.is_some()).then_some(self.mean)
+    }
+
+    /// The sample standard deviation
+    fn stdev(&self) -> Option<f64> {
+        self.mean()?;
+        (self.valid > 1).then(|| (self.m2 / (self.valid - 1) as f64).sqrt())
+    }
+}
+
+fn is_numeric(v: &Var) -> bool {
+    !matches!(v.ty, VarType::TStrf(_) | VarType::TASCII(_) | VarType::TStrl)
+}
+
+/// The missing value `.` or `.a` to `.z` a value label value stands for
+fn missing_label_code(value: i32) -> Option<String> {
+    match value.checked_sub(MISSING_LABEL_VALUE)? {
+        0 => Some(".".to_string()),
+        n @ 1..=26 => Some(format!(".{}", (b'a' + n as u8 - 1) as char)),
+        _ => None,
+    }
+}
+
+/// A DDI 2.5 Codebook describing the file `file`, with a `<var>` in
+/// `<dataDscr>` for each variable, and the summary statistics of `stats`
+pub fn codebook(file: &str, md: &Metadata, stats: Option<&CodebookStats>) -> String {
+    let mut s = String::new();
+    writeln!(s, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(
+        s,
+        r#"<codeBook xmlns="ddi:codebook:2_5" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="ddi:codebook:2_5 http://www.ddialliance.org/Specification/DDI-Codebook/2.5/XMLSchema/codebook.xsd" version="2.5">"#
+    )
+    .unwrap();
+    let title = if md.data_label.is_empty() { file } else { md.data_label.as_str() };
+    writeln!(s, "  <stdyDscr>").unwrap();
+    writeln!(s, "    <citation>").unwrap();
+    writeln!(s, "      <titlStmt>").unwrap();
+    writeln!(s, "        <titl>{}</titl>", escape(title)).unwrap();
+    writeln!(s, "      </titlStmt>").unwrap();
+    writeln!(s, "    </citation>").unwrap();
+    writeln!(s, "  </stdyDscr>").unwrap();
+
+    writeln!(s, r#"  <fileDscr ID="F1">"#).unwrap();
+    writeln!(s, "    <fileTxt>").unwrap();
+    writeln!(s, "      <fileName>{}</fileName>", escape(file)).unwrap();
+    writeln!(s, "      <dimensns>").unwrap();
+    writeln!(s, "        <caseQnty>{}</caseQnty>", md.nobs).unwrap();
+    writeln!(s, "        <varQnty>{}</varQnty>", md.nvars).unwrap();
+    writeln!(s, "      </dimensns>").unwrap();
+    writeln!(s, "      <fileType>Stata {}</fileType>", md.version).unwrap();
+    writeln!(s, "    </fileTxt>").unwrap();
+    for n in md.characteristics.get("_dta").map(notes).unwrap_or_default() {
+        writeln!(s, "    <notes>{}</notes>", escape(n)).unwrap();
+    }
+    writeln!(s, "  </fileDscr>").unwrap();
+
+    writeln!(s, "  <dataDscr>").unwrap();
+    for (i, v) in md.vars.iter().enumerate() {
+        write_var(&mut s, i, v, md, stats.map(|st| &st.vars[i]));
+    }
+    writeln!(s, "  </dataDscr>").unwrap();
+    writeln!(s, "</codeBook>").unwrap();
+    s
+}
+
+fn write_var(s: &mut String, i: usize, v: &Var, md: &Metadata, stats: Option<&VarStats>) {
+    let numeric = is_numeric(v);
+    let discrete = v.dictionary.is_some() || !matches!(v.ty, VarType::TFloat | VarType::TDouble);
+    let intrvl = if !numeric || discrete { "discrete" } else { "contin" };
+    writeln!(s, r#"    <var ID="V{}" name="{}" files="F1" intrvl="{}">"#, i + 1, escape(&v.name), intrvl).unwrap();
+    if !v.var_label.is_empty() {
+        writeln!(s, "      <labl>{}</labl>", escape(&v.var_label)).unwrap();
+    }
+
+    //Stata's missing values: `.`, those with a label, and those found
+    let labels: Vec<(i32, &str)> = v
+        .dictionary
+        .iter()
+        .flat_map(|t| t.values.iter().copied().zip(t.labels.iter().map(|l| l.as_str())))
+        .collect();
+    if numeric {
+        let mut codes = vec![".".to_string()];
+        codes.extend(labels.iter().filter_map(|&(x, _)| missing_label_code(x)));
+        codes.extend(stats.iter().flat_map(|st| st.missing.keys().cloned()));
+        codes.sort();
+        codes.dedup();
+        writeln!(s, "      <invalrng>").unwrap();
+        for c in codes {
+            writeln!(s, r#"        <item VALUE="{}"/>"#, c).unwrap();
+        }
+        writeln!(s, "      </invalrng>").unwrap();
+    }
+
+    if let Some(st) = stats {
+        writeln!(s, r#"      <sumStat type="vald">{}</sumStat>"#, st.valid).unwrap();
+        writeln!(s, r#"      <sumStat type="invd">{}</sumStat>"#, st.invalid).unwrap();
+        if numeric {
+            let values = [("min", st.min), ("max", st.max), ("mean", st.mean()), ("stdev", st.stdev())];
+            for (ty, x) in values {
+                if let Some(x) = x {
+                    writeln!(s, r#"      <sumStat type="{}">{}</sumStat>"#, ty, x).unwrap();
+                }
+            }
+        }
+    }
+
+    for &(x, label) in &labels {
+        let code = missing_label_code(x);
+        let missing = if code.is_some() { r#" missing="Y""# } else { "" };
+        writeln!(s, "      <catgry{}>", missing).unwrap();
+        writeln!(s, "        <catValu>{}</catValu>", code.clone().unwrap_or_else(|| x.to_string())).unwrap();
+        writeln!(s, "        <labl>{}</labl>", escape(label)).unwrap();
+        if let Some(st) = stats {
+            let n = match &code {
+                Some(c) => st.missing.get(c),
+                None => st.freq.get(&(x as i64)),
+            };
+            writeln!(s, r#"        <catStat type="freq">{}</catStat>"#, n.copied().unwrap_or(0)).unwrap();
+        }
+        writeln!(s, "      </catgry>").unwrap();
+    }
+
+    let ty = if numeric { "numeric" } else { "character" };
+    let category = match DateKind::from_format(&v.format) {
+        Some(DateKind::Millis) => r#" category="time""#,
+        Some(_) => r#" category="date""#,
+        None => "",
+    };
+    writeln!(
+        s,
+        r#"      <varFormat type="{}" schema="other" formatname="{}"{}>{}</varFormat>"#,
+        ty,
+        escape(&v.format),
+        category,
+        v.ty
+    )
+    .unwrap();
+    for n in md.characteristics.get(&v.name).map(notes).unwrap_or_default() {
+        writeln!(s, "      <notes>{}</notes>", escape(n)).unwrap();
+    }
+    writeln!(s, "    </var>").unwrap();
+}
+
+/// Escape text for XML content and attribute values
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            //Control characters other than tabs and line breaks are not allowed
+            c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
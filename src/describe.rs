@@ -0,0 +1,100 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use super::stata::file::{Characteristics, Metadata};
+
+/// Schema metadata key of the characteristics of the file, as a JSON
+/// object of objects: `{"_dta": {"note0": "1", ...}, "income": {...}}`
+pub const CHARACTERISTICS: &str = "dta2pqt.characteristics";
+
+/// The schema metadata recording `chars`, if there are any
+pub fn characteristics_metadata(chars: &Characteristics) -> HashMap<String, String> {
+    if chars.is_empty() {
+        return HashMap::new();
+    }
+    HashMap::from([(CHARACTERISTICS.to_string(), serde_json::to_string(chars).unwrap())])
+}
+
+/// The notes among the characteristics of a variable or the dataset,
+/// in order. `note0` counts them, and notes that were dropped leave gaps.
+pub fn notes(chars: &BTreeMap<String, String>) -> Vec<&str> {
+    let mut notes: Vec<(usize, &str)> = chars
+        .iter()
+        .filter_map(|(k, v)| {
+            let n: usize = k.strip_prefix("note")?.parse().ok()?;
+            (n > 0).then_some((n, v.as_str()))
+        })
+        .collect();
+    notes.sort();
+    notes.into_iter().map(|(_, v)| v).collect()
+}
+
+fn is_note(name: &str) -> bool {
+    name.strip_prefix("note").is_some_and(|n| n.parse::<usize>().is_ok())
+}
+
+/// A description of the file `file` in the manner of Stata's `describe`,
+/// followed by its notes and other characteristics
+pub fn describe(file: &str, md: &Metadata) -> String {
+    let mut s = String::new();
+    writeln!(s, "Contains data from {}", file).unwrap();
+    if !md.data_label.is_empty() {
+        writeln!(s, " Label:        {}", md.data_label).unwrap();
+    }
+    writeln!(s, " Format:       {}", md.version).unwrap();
+    writeln!(s, " Observations: {}", md.nobs).unwrap();
+    writeln!(s, " Variables:    {}", md.nvars).unwrap();
+    if !md.sortlist.is_empty() {
+        let sorted: Vec<&str> = md.sortlist.iter().map(|&i| md.vars[i].name.as_str()).collect();
+        writeln!(s, " Sorted by:    {}", sorted.join(" ")).unwrap();
+    }
+
+    let rows: Vec<[String; 5]> = md
+        .vars
+        .iter()
+        .map(|v| [v.name.clone(), v.ty.to_string(), v.format.clone(), v.value_label.clone(), v.var_label.clone()])
+        .collect();
+    let header = ["Variable", "Type", "Format", "Value label", "Variable label"].map(String::from);
+    let widths: Vec<usize> = (0..4)
+        .map(|k| rows.iter().chain([&header]).map(|r| r[k].chars().count()).max().unwrap())
+        .collect();
+    writeln!(s).unwrap();
+    for r in [&header].into_iter().chain(&rows) {
+        let mut line = String::new();
+        for (k, w) in widths.iter().enumerate() {
+            write!(line, "{:<w$}  ", r[k], w = w).unwrap();
+        }
+        line.push_str(&r[4]);
+        writeln!(s, "{}", line.trim_end()).unwrap();
+    }
+
+    //Notes of the dataset first, then those of the variables in order
+    let owners: Vec<&str> = ["_dta"].into_iter().chain(md.vars.iter().map(|v| v.name.as_str())).collect();
+    let noted: Vec<(&str, Vec<&str>)> = owners
+        .iter()
+        .filter_map(|&o| md.characteristics.get(o).map(|c| (o, notes(c))))
+        .filter(|(_, n)| !n.is_empty())
+        .collect();
+    if !noted.is_empty() {
+        writeln!(s, "\nNotes:").unwrap();
+        for (owner, notes) in noted {
+            writeln!(s, "  {}:", owner).unwrap();
+            for (i, n) in notes.iter().enumerate() {
+                writeln!(s, "    {}. {}", i + 1, n).unwrap();
+            }
+        }
+    }
+
+    let others: Vec<(&str, &str, &str)> = md
+        .characteristics
+        .iter()
+        .flat_map(|(o, c)| c.iter().filter(|(k, _)| !is_note(k)).map(move |(k, v)| (o.as_str(), k.as_str(), v.as_str())))
+        .collect();
+    if !others.is_empty() {
+        writeln!(s, "\nCharacteristics:").unwrap();
+        for (owner, name, contents) in others {
+            writeln!(s, "  {}[{}]: {}", owner, name, contents).unwrap();
+        }
+    }
+    s
+}
@@ -0,0 +1,149 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+
+use super::stata::file::Metadata;
+
+/// Schema metadata key of the `svyset` survey design, as `SvySet` JSON
+pub const SVYSET: &str = "dta2pqt.svyset";
+/// Schema metadata key of the `xtset` or `tsset` settings, as `XtSet` JSON
+pub const XTSET: &str = "dta2pqt.xtset";
+
+/// The survey design set by `svyset`, from the `_svy_*` characteristics
+/// of the dataset. Settings that were not given are `null`.
+#[derive(Debug, Serialize)]
+pub struct SvySet {
+    /// `pweight` or `iweight`
+    pub weight_type: Option<String>,
+    pub weight: Option<String>,
+    /// Sampling stages, the first stage first
+    pub stages: Vec<SvyStage>,
+    /// Variance estimation, such as `linearized` or `brr`
+    pub vce: Option<String>,
+    /// Handling of strata with one sampling unit, such as `missing` or `certainty`
+    pub single_unit: Option<String>,
+    pub poststrata: Option<String>,
+    pub postweight: Option<String>,
+}
+
+/// A sampling stage of a survey design
+#[derive(Debug, Serialize)]
+pub struct SvyStage {
+    /// The sampling units, or `_n` for the observations
+    pub psu: Option<String>,
+    pub strata: Option<String>,
+    /// The finite population correction
+    pub fpc: Option<String>,
+}
+
+/// The panel and time variables set by `xtset` or `tsset`, from the
+/// `_TS*` characteristics of the dataset, or `iis` and `tis` as set by
+/// older versions of Stata
+#[derive(Debug, Serialize)]
+pub struct XtSet {
+    /// `null` for time series set by `tsset` without a panel
+    pub panel: Option<String>,
+    pub time: Option<String>,
+    /// The time between periods, in the units of the time variable
+    pub delta: Option<f64>,
+    /// The display format of the time variable, giving its units
+    pub time_format: Option<String>,
+}
+
+impl SvySet {
+    pub fn from_characteristics(chars: &BTreeMap<String, String>, rename: impl Fn(&str) -> String) -> Option<SvySet> {
+        if !chars.keys().any(|k| k.starts_with("_svy_")) {
+            return None;
+        }
+        let get = |k: &str| chars.get(k).filter(|v| !v.is_empty()).cloned();
+        let var = |k: &str| get(k).map(|v| if v == "_n" { v } else { rename(&v) });
+        let nstages = chars
+            .keys()
+            .filter_map(|k| k.strip_prefix("_svy_su")?.parse::<usize>().ok())
+            .chain(get("_svy_stages").and_then(|n| n.parse().ok()))
+            .max()
+            .unwrap_or(0);
+        let stages = (1..=nstages)
+            .map(|k| SvyStage {
+                psu: var(&format!("_svy_su{}", k)),
+                strata: var(&format!("_svy_strata{}", k)),
+                fpc: var(&format!("_svy_fpc{}", k)),
+            })
+            .collect();
+        Some(SvySet {
+            weight_type: get("_svy_wtype").filter(|t| t != "none"),
+            weight: var("_svy_wvar"),
+            stages,
+            vce: get("_svy_vce"),
+            single_unit: get("_svy_singleunit"),
+            poststrata: var("_svy_poststrata"),
+            postweight: var("_svy_postweight"),
+        })
+    }
+}
+
+impl XtSet {
+    /// The settings, with the format of the time variable looked up by
+    /// `format`, and variable names mapped by `rename`
+    pub fn from_characteristics(
+        chars: &BTreeMap<String, String>,
+        format: impl Fn(&str) -> Option<String>,
+        rename: impl Fn(&str) -> String,
+    ) -> Option<XtSet> {
+        let get = |ks: &[&str]| ks.iter().find_map(|&k| chars.get(k).filter(|v| !v.is_empty()).cloned());
+        let panel = get(&["_TSpanel", "iis"]);
+        let time = get(&["_TStvar", "tis"]);
+        if panel.is_none() && time.is_none() {
+            return None;
+        }
+        Some(XtSet {
+            delta: get(&["_TSdelta"]).and_then(|d| parse_stata_double(&d)),
+            time_format: time.as_deref().and_then(format),
+            panel: panel.as_deref().map(&rename),
+            time: time.as_deref().map(&rename),
+        })
+    }
+}
+
+/// The `svyset` and `xtset` settings of the file as schema metadata,
+/// with the variables named as the output columns by `rename`
+pub fn design_metadata(md: &Metadata, rename: impl Fn(&str) -> String) -> HashMap<String, String> {
+    let mut out = HashMap::new();
+    let Some(chars) = md.characteristics.get("_dta") else {
+        return out;
+    };
+    if let Some(svy) = SvySet::from_characteristics(chars, &rename) {
+        out.insert(SVYSET.to_string(), serde_json::to_string(&svy).unwrap());
+    }
+    let format = |name: &str| md.vars.iter().find(|v| v.name == name).map(|v| v.format.clone());
+    if let Some(xt) = XtSet::from_characteristics(chars, format, &rename) {
+        out.insert(XTSET.to_string(), serde_json::to_string(&xt).unwrap());
+    }
+    out
+}
+
+/// A number as Stata writes it in characteristics: in decimal, or in
+/// its `%21x` hexadecimal format as in `+1.8000000000000X+001` for 3
+fn parse_stata_double(s: &str) -> Option<f64> {
+    if let Ok(x) = s.parse() {
+        return Some(x);
+    }
+    let (mantissa, exp) = s.split_once(['X', 'x'])?;
+    let (sign, mantissa) = match mantissa.strip_prefix('-') {
+        Some(m) => (-1.0, m),
+        None => (1.0, mantissa.strip_prefix('+').unwrap_or(mantissa)),
+    };
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let mut x = u64::from_str_radix(int, 16).ok()? as f64;
+    let mut scale = 1.0 / 16.0;
+    for c in frac.chars() {
+        x += c.to_digit(16)? as f64 * scale;
+        scale /= 16.0;
+    }
+    let (esign, exp) = match exp.strip_prefix('-') {
+        Some(e) => (-1, e),
+        None => (1, exp.strip_prefix('+').unwrap_or(exp)),
+    };
+    let exp = esign * i32::from_str_radix(exp, 16).ok()?;
+    Some(sign * x * 2f64.powi(exp))
+}
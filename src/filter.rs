@@ -0,0 +1,618 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use arrow::array::{Array, ArrayRef, AsArray, BooleanArray, StringArray};
+use arrow::compute::filter_record_batch;
+use arrow::datatypes::{DataType, Float32Type, Float64Type, Int16Type, Int32Type, Int8Type};
+use arrow_array::RecordBatch;
+
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_while};
+use nom::character::complete::{char, digit0, digit1, multispace0, one_of, satisfy};
+use nom::combinator::{all_consuming, map, not, opt, peek, recognize, value};
+use nom::multi::{many0, separated_list0};
+use nom::sequence::{delimited, pair, preceded, terminated, tuple};
+use nom::IResult;
+
+use super::stata::{ValueLabelTable, Var, VarType};
+use super::translate::{make_schema_with_missing_codes, MISSING_CODE_OF};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Func {
+    Missing,
+    InList,
+    InRange,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Num(f64),
+    /// `.` is 0, `.a` to `.z` are 1 to 26
+    Missing(u8),
+    Str(String),
+    /// A value label, as in `"North":regionlbl`
+    Label(String, String),
+    Var(String),
+    /// A column of the batch, once bound
+    Column(usize),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Numeric,
+    String,
+}
+
+/// A Stata style `if` expression, such as `year >= 2015 & !missing(income)`
+///
+/// Missing values are larger than any number, with `.` < `.a` < ... < `.z`,
+/// and are true when used as a condition. String variables are missing
+/// when empty.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    text: String,
+    expr: Expr,
+}
+
+/// A `Filter` with its variables resolved to the columns of the
+/// batches made by `parse_data` with missing codes
+pub struct BoundFilter {
+    expr: Expr,
+    /// Data and missing code column of each column referred to
+    columns: Vec<(usize, Option<usize>)>,
+}
+
+impl Filter {
+    pub fn parse(s: &str) -> Result<Filter, String> {
+        match all_consuming(delimited(multispace0, p_or, multispace0))(s) {
+            Ok((_, expr)) => Ok(Filter { text: s.to_string(), expr }),
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                Err(format!("Invalid expression at \"{}\"", e.input))
+            }
+            Err(nom::Err::Incomplete(_)) => Err("Incomplete expression".to_string()),
+        }
+    }
+
+    /// Whether the expression refers to value labels, which must then be read
+    pub fn uses_value_labels(&self) -> bool {
+        fn walk(e: &Expr) -> bool {
+            match e {
+                Expr::Label(..) => true,
+                Expr::Not(a) | Expr::Neg(a) => walk(a),
+                Expr::Binary(_, a, b) => walk(a) || walk(b),
+                Expr::Call(_, args) => args.iter().any(walk),
+                _ => false,
+            }
+        }
+        walk(&self.expr)
+    }
+
+    /// Whether the expression refers to the variable `name`
+    pub fn uses_variable(&self, name: &str) -> bool {
+        fn walk(e: &Expr, name: &str) -> bool {
+            match e {
+                Expr::Var(v) => v == name,
+                Expr::Not(a) | Expr::Neg(a) => walk(a, name),
+                Expr::Binary(_, a, b) => walk(a, name) || walk(b, name),
+                Expr::Call(_, args) => args.iter().any(|a| walk(a, name)),
+                _ => false,
+            }
+        }
+        walk(&self.expr, name)
+    }
+
+    /// Resolve the variables and value labels of the expression.
+    /// Panics on unknown names or type mismatches.
+    pub fn bind(&self, vars: &[Var], value_labels: &[Arc<ValueLabelTable>]) -> BoundFilter {
+        let schema = make_schema_with_missing_codes(vars);
+        let mut columns = Vec::new();
+        let mut binder = Binder { vars, value_labels, schema: &schema, columns: &mut columns };
+        let (expr, kind) = binder
+            .bind(&self.expr)
+            .unwrap_or_else(|e| panic!("--if {}: {}", self.text, e));
+        if kind != Kind::Numeric {
+            panic!("--if {}: the condition must be numeric", self.text);
+        }
+        BoundFilter { expr, columns }
+    }
+}
+
+struct Binder<'a> {
+    vars: &'a [Var],
+    value_labels: &'a [Arc<ValueLabelTable>],
+    schema: &'a arrow::datatypes::Schema,
+    columns: &'a mut Vec<(usize, Option<usize>)>,
+}
+
+impl Binder<'_> {
+    fn bind(&mut self, e: &Expr) -> Result<(Expr, Kind), String> {
+        Ok(match e {
+            Expr::Num(_) | Expr::Missing(_) => (e.clone(), Kind::Numeric),
+            Expr::Str(_) => (e.clone(), Kind::String),
+            Expr::Label(text, labname) => {
+                let table = self
+                    .value_labels
+                    .iter()
+                    .find(|t| &t.labelname == labname)
+                    .ok_or_else(|| format!("no value label {}", labname))?;
+                let i = table
+                    .labels
+                    .iter()
+                    .position(|l| l == text)
+                    .ok_or_else(|| format!("\"{}\" is not a label of {}", text, labname))?;
+                (Expr::Num(table.values[i] as f64), Kind::Numeric)
+            }
+            Expr::Var(name) => {
+                let v = self
+                    .vars
+                    .iter()
+                    .find(|v| &v.name == name)
+                    .ok_or_else(|| format!("no variable {}", name))?;
+                let kind = match v.ty {
+                    VarType::TStrf(_) | VarType::TASCII(_) | VarType::TStrl => Kind::String,
+                    _ => Kind::Numeric,
+                };
+                let data = self.schema.index_of(name).unwrap();
+                let codes = self
+                    .schema
+                    .fields()
+                    .iter()
+                    .position(|f| f.metadata().get(MISSING_CODE_OF) == Some(name));
+                self.columns.push((data, codes));
+                (Expr::Column(self.columns.len() - 1), kind)
+            }
+            Expr::Column(_) => unreachable!(),
+            Expr::Not(a) => (Expr::Not(Box::new(self.numeric(a)?)), Kind::Numeric),
+            Expr::Neg(a) => (Expr::Neg(Box::new(self.numeric(a)?)), Kind::Numeric),
+            Expr::Binary(op, a, b) => {
+                let (a, ka) = self.bind(a)?;
+                let (b, kb) = self.bind(b)?;
+                let comparison = matches!(op, BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge);
+                if ka != kb || (!comparison && ka == Kind::String) {
+                    return Err("type mismatch".to_string());
+                }
+                (Expr::Binary(*op, Box::new(a), Box::new(b)), Kind::Numeric)
+            }
+            Expr::Call(f, args) => {
+                let n = args.len();
+                let ok = match f {
+                    Func::Missing => n >= 1,
+                    Func::InList => n >= 2,
+                    Func::InRange => n == 3,
+                };
+                if !ok {
+                    return Err(format!("wrong number of arguments to {:?}", f).to_lowercase());
+                }
+                let mut bound = Vec::new();
+                let mut kinds = Vec::new();
+                for a in args {
+                    let (a, k) = self.bind(a)?;
+                    bound.push(a);
+                    kinds.push(k);
+                }
+                if *f != Func::Missing && kinds.iter().any(|k| *k != kinds[0]) {
+                    return Err("type mismatch".to_string());
+                }
+                (Expr::Call(*f, bound), Kind::Numeric)
+            }
+        })
+    }
+
+    fn numeric(&mut self, e: &Expr) -> Result<Expr, String> {
+        match self.bind(e)? {
+            (e, Kind::Numeric) => Ok(e),
+            _ => Err("type mismatch".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Val<'a> {
+    Num(f64),
+    Miss(u8),
+    Str(Cow<'a, str>),
+}
+
+impl Val<'_> {
+    fn is_missing(&self) -> bool {
+        match self {
+            Val::Num(_) => false,
+            Val::Miss(_) => true,
+            Val::Str(s) => s.is_empty(),
+        }
+    }
+
+    fn truth(&self) -> bool {
+        match self {
+            Val::Num(x) => *x != 0.0,
+            _ => true,
+        }
+    }
+}
+
+fn bool_val<'a>(b: bool) -> Val<'a> {
+    Val::Num(if b { 1.0 } else { 0.0 })
+}
+
+/// Stata's ordering: numbers, then `.`, `.a`, ..., `.z`
+fn compare(a: &Val, b: &Val) -> Ordering {
+    match (a, b) {
+        (Val::Num(x), Val::Num(y)) => x.partial_cmp(y).unwrap_or(Ordering::Equal),
+        (Val::Num(_), Val::Miss(_)) => Ordering::Less,
+        (Val::Miss(_), Val::Num(_)) => Ordering::Greater,
+        (Val::Miss(x), Val::Miss(y)) => x.cmp(y),
+        (Val::Str(x), Val::Str(y)) => x.cmp(y),
+        _ => unreachable!(),
+    }
+}
+
+impl BoundFilter {
+    /// The rows of `batch` for which the expression is true
+    pub fn filter(&self, batch: &RecordBatch) -> RecordBatch {
+        let columns: Vec<(&ArrayRef, Option<&StringArray>)> = self
+            .columns
+            .iter()
+            .map(|&(d, c)| (batch.column(d), c.map(|c| batch.column(c).as_string::<i32>())))
+            .collect();
+        let keep: BooleanArray = (0..batch.num_rows())
+            .map(|row| Some(self.eval(&self.expr, &columns, row).truth()))
+            .collect();
+        filter_record_batch(batch, &keep).unwrap()
+    }
+
+    fn eval<'a>(&self, e: &'a Expr, columns: &[(&'a ArrayRef, Option<&'a StringArray>)], row: usize) -> Val<'a> {
+        match e {
+            Expr::Num(x) => Val::Num(*x),
+            Expr::Missing(c) => Val::Miss(*c),
+            Expr::Str(s) => Val::Str(Cow::Borrowed(s)),
+            Expr::Column(i) => column_value(columns[*i].0, columns[*i].1, row),
+            Expr::Not(a) => bool_val(!self.eval(a, columns, row).truth()),
+            Expr::Neg(a) => match self.eval(a, columns, row) {
+                Val::Num(x) => Val::Num(-x),
+                v => v,
+            },
+            Expr::Binary(BinOp::Or, a, b) => {
+                bool_val(self.eval(a, columns, row).truth() || self.eval(b, columns, row).truth())
+            }
+            Expr::Binary(BinOp::And, a, b) => {
+                bool_val(self.eval(a, columns, row).truth() && self.eval(b, columns, row).truth())
+            }
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (self.eval(a, columns, row), self.eval(b, columns, row));
+                match op {
+                    BinOp::Eq => bool_val(compare(&a, &b) == Ordering::Equal),
+                    BinOp::Ne => bool_val(compare(&a, &b) != Ordering::Equal),
+                    BinOp::Lt => bool_val(compare(&a, &b) == Ordering::Less),
+                    BinOp::Le => bool_val(compare(&a, &b) != Ordering::Greater),
+                    BinOp::Gt => bool_val(compare(&a, &b) == Ordering::Greater),
+                    BinOp::Ge => bool_val(compare(&a, &b) != Ordering::Less),
+                    _ => match (a, b) {
+                        (Val::Num(x), Val::Num(y)) => match op {
+                            BinOp::Add => Val::Num(x + y),
+                            BinOp::Sub => Val::Num(x - y),
+                            BinOp::Mul => Val::Num(x * y),
+                            BinOp::Div if y == 0.0 => Val::Miss(0),
+                            _ => Val::Num(x / y),
+                        },
+                        _ => Val::Miss(0),
+                    },
+                }
+            }
+            Expr::Call(Func::Missing, args) => bool_val(args.iter().any(|a| self.eval(a, columns, row).is_missing())),
+            Expr::Call(Func::InList, args) => {
+                let z = self.eval(&args[0], columns, row);
+                bool_val(args[1..].iter().any(|a| compare(&z, &self.eval(a, columns, row)) == Ordering::Equal))
+            }
+            Expr::Call(Func::InRange, args) => {
+                let z = self.eval(&args[0], columns, row);
+                let lo = self.eval(&args[1], columns, row);
+                let hi = self.eval(&args[2], columns, row);
+                //Missing bounds are unbounded, but a missing value is never in range
+                bool_val(
+                    !z.is_missing()
+                        && (lo.is_missing() || compare(&z, &lo) != Ordering::Less)
+                        && (hi.is_missing() || compare(&z, &hi) != Ordering::Greater),
+                )
+            }
+            Expr::Label(..) | Expr::Var(_) => unreachable!(),
+        }
+    }
+}
+
+fn column_value<'a>(a: &'a ArrayRef, codes: Option<&'a StringArray>, row: usize) -> Val<'a> {
+    if a.is_null(row) {
+        let code = codes
+            .filter(|c| c.is_valid(row))
+            .map(|c| c.value(row).as_bytes())
+            .and_then(|c| c.get(1))
+            .map(|c| c - b'a' + 1);
+        return Val::Miss(code.unwrap_or(0));
+    }
+    match a.data_type() {
+        DataType::Int8 => Val::Num(a.as_primitive::<Int8Type>().value(row) as f64),
+        DataType::Int16 => Val::Num(a.as_primitive::<Int16Type>().value(row) as f64),
+        DataType::Int32 => Val::Num(a.as_primitive::<Int32Type>().value(row) as f64),
+        DataType::Float32 => Val::Num(a.as_primitive::<Float32Type>().value(row) as f64),
+        DataType::Float64 => Val::Num(a.as_primitive::<Float64Type>().value(row)),
+        DataType::Utf8 => Val::Str(Cow::Borrowed(a.as_string::<i32>().value(row))),
+        DataType::Binary => {
+            let s = a.as_binary::<i32>().value(row);
+            Val::Str(String::from_utf8_lossy(s.strip_suffix(b"\0").unwrap_or(s)))
+        }
+        t => panic!("Cannot filter on {}", t),
+    }
+}
+
+fn ws<'a, O>(p: impl FnMut(&'a str) -> IResult<&'a str, O>) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    delimited(multispace0, p, multispace0)
+}
+
+fn fold(first: Expr, rest: Vec<(BinOp, Expr)>) -> Expr {
+    rest.into_iter()
+        .fold(first, |a, (op, b)| Expr::Binary(op, Box::new(a), Box::new(b)))
+}
+
+fn p_or(s: &str) -> IResult<&str, Expr> {
+    let (s, first) = p_and(s)?;
+    let (s, rest) = many0(pair(value(BinOp::Or, ws(char('|'))), p_and))(s)?;
+    Ok((s, fold(first, rest)))
+}
+
+fn p_and(s: &str) -> IResult<&str, Expr> {
+    let (s, first) = p_rel(s)?;
+    let (s, rest) = many0(pair(value(BinOp::And, ws(char('&'))), p_rel))(s)?;
+    Ok((s, fold(first, rest)))
+}
+
+fn p_rel(s: &str) -> IResult<&str, Expr> {
+    let (s, first) = p_add(s)?;
+    let relop = ws(alt((
+        value(BinOp::Eq, tag("==")),
+        value(BinOp::Ne, tag("!=")),
+        value(BinOp::Ne, tag("~=")),
+        value(BinOp::Ge, tag(">=")),
+        value(BinOp::Le, tag("<=")),
+        value(BinOp::Gt, tag(">")),
+        value(BinOp::Lt, tag("<")),
+    )));
+    let (s, rest) = opt(pair(relop, p_add))(s)?;
+    Ok((s, fold(first, rest.into_iter().collect())))
+}
+
+fn p_add(s: &str) -> IResult<&str, Expr> {
+    let (s, first) = p_mul(s)?;
+    let op = ws(alt((value(BinOp::Add, char('+')), value(BinOp::Sub, char('-')))));
+    let (s, rest) = many0(pair(op, p_mul))(s)?;
+    Ok((s, fold(first, rest)))
+}
+
+fn p_mul(s: &str) -> IResult<&str, Expr> {
+    let (s, first) = p_unary(s)?;
+    let op = ws(alt((value(BinOp::Mul, char('*')), value(BinOp::Div, char('/')))));
+    let (s, rest) = many0(pair(op, p_unary))(s)?;
+    Ok((s, fold(first, rest)))
+}
+
+fn p_unary(s: &str) -> IResult<&str, Expr> {
+    alt((
+        map(preceded(ws(terminated(one_of("!~"), not(char('=')))), p_unary), |e| Expr::Not(Box::new(e))),
+        map(preceded(ws(char('-')), p_unary), |e| Expr::Neg(Box::new(e))),
+        ws(p_primary),
+    ))(s)
+}
+
+fn p_primary(s: &str) -> IResult<&str, Expr> {
+    alt((
+        delimited(char('('), ws(p_or), char(')')),
+        map(p_number, Expr::Num),
+        map(p_missing, Expr::Missing),
+        p_string,
+        p_call,
+        map(p_ident, |n| Expr::Var(n.to_string())),
+    ))(s)
+}
+
+fn p_number(s: &str) -> IResult<&str, f64> {
+    let exponent = tuple((one_of("eE"), opt(one_of("+-")), digit1));
+    let (s, n) = recognize(tuple((
+        alt((recognize(pair(digit1, opt(pair(char('.'), digit0)))), recognize(pair(char('.'), digit1)))),
+        opt(exponent),
+    )))(s)?;
+    Ok((s, n.parse().unwrap()))
+}
+
+fn p_missing(s: &str) -> IResult<&str, u8> {
+    let (s, _) = char('.')(s)?;
+    let (s, c) = opt(satisfy(|c| c.is_ascii_lowercase()))(s)?;
+    let (s, _) = not(peek(satisfy(|c| c.is_ascii_alphanumeric() || c == '_')))(s)?;
+    Ok((s, c.map_or(0, |c| c as u8 - b'a' + 1)))
+}
+
+fn p_string(s: &str) -> IResult<&str, Expr> {
+    let (s, text) = delimited(char('"'), take_while(|c| c != '"'), char('"'))(s)?;
+    let (s, labname) = opt(preceded(char(':'), p_ident))(s)?;
+    Ok((
+        s,
+        match labname {
+            Some(l) => Expr::Label(text.to_string(), l.to_string()),
+            None => Expr::Str(text.to_string()),
+        },
+    ))
+}
+
+fn p_call(s: &str) -> IResult<&str, Expr> {
+    let (s, f) = alt((
+        value(Func::Missing, tag("missing")),
+        value(Func::InList, tag("inlist")),
+        value(Func::InRange, tag("inrange")),
+    ))(s)?;
+    let (s, args) = preceded(
+        pair(multispace0, char('(')),
+        terminated(separated_list0(char(','), ws(p_or)), char(')')),
+    )(s)?;
+    Ok((s, Expr::Call(f, args)))
+}
+
+fn p_ident(s: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        satisfy(|c| c.is_ascii_alphabetic() || c == '_'),
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+    ))(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Float64Array, Int32Array};
+    use arrow::datatypes::Int32Type;
+
+    fn var(name: &str, ty: VarType, value_label: &str) -> Var {
+        Var {
+            ty,
+            name: name.to_string(),
+            format: String::new(),
+            value_label: value_label.to_string(),
+            var_label: String::new(),
+            dictionary: None,
+        }
+    }
+
+    /// The ids of the rows of the test batch for which `expr` is true.
+    ///
+    /// | id | x  | s   | region |
+    /// |----|----|-----|--------|
+    /// | 0  | 1  | "b" | 1      |
+    /// | 1  | 5  | "a" | 2      |
+    /// | 2  | .  | ""  | 3      |
+    /// | 3  | .a | "c" | 1      |
+    /// | 4  | .z | "b" | .      |
+    fn rows(expr: &str) -> Vec<i32> {
+        let vars = [
+            var("id", VarType::TLong, ""),
+            var("x", VarType::TDouble, ""),
+            var("s", VarType::TStrf(4), ""),
+            var("region", VarType::TLong, "regionlbl"),
+        ];
+        let labels = [Arc::new(ValueLabelTable {
+            labelname: "regionlbl".to_string(),
+            labels: vec!["North".to_string(), "South".to_string()],
+            values: vec![1, 2],
+        })];
+        let schema = Arc::new(make_schema_with_missing_codes(&vars));
+        let none: Option<&str> = None;
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![0, 1, 2, 3, 4])),
+                Arc::new(Float64Array::from(vec![Some(1.0), Some(5.0), None, None, None])),
+                Arc::new(StringArray::from(vec!["b", "a", "", "c", "b"])),
+                Arc::new(Int32Array::from(vec![Some(1), Some(2), Some(3), Some(1), None])),
+                Arc::new(StringArray::from(vec![none, none, none, none, none])),
+                Arc::new(StringArray::from(vec![None, None, Some("."), Some(".a"), Some(".z")])),
+                Arc::new(StringArray::from(vec![None, None, None, None, Some(".")])),
+            ],
+        )
+        .unwrap();
+        let filter = Filter::parse(expr).unwrap().bind(&vars, &labels);
+        let out = filter.filter(&batch);
+        out.column(0).as_primitive::<Int32Type>().values().to_vec()
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(rows("id == 0 | id == 1 & x == 1"), [0]);
+        assert_eq!(rows("(id == 0 | id == 1) & x == 1"), [0]);
+        assert_eq!(rows("id + 1 * 2 == 3"), [1]);
+        assert_eq!(rows("(id + 1) * 2 == 4"), [1]);
+        assert_eq!(rows("-id + 4 == 2"), [2]);
+        assert_eq!(rows("!(id < 3)"), [3, 4]);
+        assert_eq!(rows("id != 1 & id ~= 2 & id < 4"), [0, 3]);
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(rows("x >= 1 & x <= 5"), [0, 1]);
+        assert_eq!(rows("x > 1.5e0"), [1, 2, 3, 4]);
+        assert_eq!(rows("x / 0 == ."), [0, 1, 2, 3, 4]);
+        assert_eq!(rows("s == \"b\""), [0, 4]);
+        assert_eq!(rows("s < \"b\""), [1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "type mismatch")]
+    fn string_number_mismatch() {
+        rows("s == 1");
+    }
+
+    #[test]
+    fn missing_ordering() {
+        assert_eq!(rows("x < ."), [0, 1]);
+        assert_eq!(rows("x == ."), [2]);
+        assert_eq!(rows("x > ."), [3, 4]);
+        assert_eq!(rows("x >= .a & x < .z"), [3]);
+        assert_eq!(rows("x == .z"), [4]);
+        assert_eq!(rows("1e300 < ."), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn missing_function() {
+        assert_eq!(rows("missing(x)"), [2, 3, 4]);
+        assert_eq!(rows("missing(s)"), [2]);
+        assert_eq!(rows("!missing(x, region)"), [0, 1]);
+        //A missing value is true as a condition
+        assert_eq!(rows("x"), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn inlist() {
+        assert_eq!(rows("inlist(x, 1, 5)"), [0, 1]);
+        assert_eq!(rows("inlist(x, .a, .z)"), [3, 4]);
+        assert_eq!(rows("inlist(x, .)"), [2]);
+        assert_eq!(rows("inlist(s, \"a\", \"c\")"), [1, 3]);
+    }
+
+    #[test]
+    fn inrange() {
+        assert_eq!(rows("inrange(x, 1, 5)"), [0, 1]);
+        assert_eq!(rows("inrange(x, 2, 10)"), [1]);
+        //Missing bounds are unbounded, missing values are never in range
+        assert_eq!(rows("inrange(x, 2, .)"), [1]);
+        assert_eq!(rows("inrange(x, ., .)"), [0, 1]);
+        assert_eq!(rows("inrange(x, .a, .z)"), [0, 1]);
+        assert_eq!(rows("inrange(s, \"a\", \"b\")"), [0, 1, 4]);
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(rows("region == \"North\":regionlbl"), [0, 3]);
+        assert_eq!(rows("inlist(region, \"South\":regionlbl, 3)"), [1, 2]);
+        assert!(Filter::parse("region == \"North\":regionlbl").unwrap().uses_value_labels());
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a label of regionlbl")]
+    fn unknown_label() {
+        rows("region == \"West\":regionlbl");
+    }
+}
@@ -0,0 +1,228 @@
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use mmap_rs::{Mmap, MmapOptions};
+
+use super::stata::file::{parse_head_new, parse_header_new, Metadata};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// Bytes read from the start of a pipe to find the `<map>` of a DTA 117+ file
+const STREAM_HEAD_LEN: usize = 4096;
+
+/// The input path standing for standard input
+pub const STDIN: &str = "-";
+
+/// Separates a zip archive from the path of a member, as in `archive.zip::dir/file.dta`
+pub const ZIP_MEMBER_SEP: &str = "::";
+
+/// The bytes of an input DTA file
+pub enum InputData {
+    Mapped(Mmap),
+    Memory(Vec<u8>),
+}
+
+impl Deref for InputData {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            InputData::Mapped(m) => m.as_slice(),
+            InputData::Memory(v) => v,
+        }
+    }
+}
+
+/// An input opened by `open_source`
+pub enum Input {
+    /// The whole file, mapped or in memory
+    Data(InputData),
+    /// A DTA 117+ file being read front to back
+    Stream(InputStream),
+}
+
+/// A DTA 117+ file read from a pipe. The metadata has been read
+/// from the head of the file and `reader` is positioned at the
+/// first data row.
+pub struct InputStream {
+    pub metadata: Metadata,
+    pub reader: Box<dyn Read + Send>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InputOptions {
+    /// Decompress into a temporary file rather than into memory
+    pub spill: bool,
+    /// Directory for temporary files. Defaults to the system one.
+    pub temp_dir: Option<PathBuf>,
+}
+
+/// Open an input DTA file
+///
+/// Plain files are memory mapped. Gzip and zstd compressed files are
+/// recognised by their magic numbers and decompressed. A zip member is
+/// named as `archive.zip::path/in/zip.dta`; an archive holding a
+/// single file can also be given by itself. Standard input (`-`),
+/// pipes and other files that cannot be mapped are spooled to a
+/// temporary file.
+pub fn open_input(spec: &Path, opts: &InputOptions) -> InputData {
+    match open_source(spec, opts, |_| false) {
+        Input::Data(d) => d,
+        Input::Stream(_) => unreachable!(),
+    }
+}
+
+/// Open an input DTA file like `open_input`, but let DTA 117+ files
+/// coming from standard input or a pipe be read front to back without
+/// spooling when `can_stream` accepts their metadata.
+pub fn open_source(spec: &Path, opts: &InputOptions, can_stream: impl Fn(&Metadata) -> bool) -> Input {
+    if spec.as_os_str() == STDIN {
+        return open_stream(Box::new(io::stdin()), opts, can_stream);
+    }
+    let s = spec.to_string_lossy();
+    if let Some((archive, member)) = s.split_once(ZIP_MEMBER_SEP) {
+        let f = File::open(archive).unwrap_or_else(|e| panic!("{}: {}", archive, e));
+        return Input::Data(read_zip_member(f, archive, Some(member), opts));
+    }
+    let mut f = File::open(spec).unwrap_or_else(|e| panic!("{}: {}", spec.display(), e));
+    if !f.metadata().unwrap().is_file() {
+        return open_stream(Box::new(f), opts, can_stream);
+    }
+    let mut magic = [0u8; 4];
+    let nmagic = read_up_to(&mut f, &mut magic);
+    let magic = &magic[..nmagic];
+    f.seek(SeekFrom::Start(0)).unwrap();
+    Input::Data(if magic.starts_with(GZIP_MAGIC) {
+        load(flate2::read::MultiGzDecoder::new(BufReader::new(f)), opts, opts.spill)
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        load(zstd::Decoder::new(f).unwrap(), opts, opts.spill)
+    } else if magic.starts_with(ZIP_MAGIC) {
+        read_zip_member(f, &s, None, opts)
+    } else {
+        map_file(&f)
+    })
+}
+
+/// Whether the input `spec` can be read more than once: a regular
+/// file, or a member of one, rather than standard input or a pipe
+pub fn is_rereadable(spec: &Path) -> bool {
+    if spec.as_os_str() == STDIN {
+        return false;
+    }
+    let s = spec.to_string_lossy();
+    let path = match s.split_once(ZIP_MEMBER_SEP) {
+        Some((archive, _)) => Path::new(archive),
+        None => spec,
+    };
+    path.metadata().is_ok_and(|m| m.is_file())
+}
+
+fn open_stream(mut r: Box<dyn Read + Send>, opts: &InputOptions, can_stream: impl Fn(&Metadata) -> bool) -> Input {
+    let mut magic = vec![0u8; 4];
+    let nmagic = read_up_to(&mut r, &mut magic);
+    magic.truncate(nmagic);
+    let mut r = Cursor::new(magic.clone()).chain(r);
+    let mut r: Box<dyn Read + Send> = if magic.starts_with(GZIP_MAGIC) {
+        Box::new(flate2::read::MultiGzDecoder::new(BufReader::new(r)))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Box::new(zstd::Decoder::new(r).unwrap())
+    } else if magic.starts_with(ZIP_MAGIC) {
+        //Zip archives keep their directory at the end
+        let mut tmp = temp_file(opts);
+        io::copy(&mut r, &mut tmp).unwrap();
+        return Input::Data(read_zip_member(tmp, STDIN, None, opts));
+    } else {
+        Box::new(r)
+    };
+
+    let mut head = vec![0u8; STREAM_HEAD_LEN];
+    let nhead = read_up_to(&mut r, &mut head);
+    head.truncate(nhead);
+    if let Ok((_, header)) = parse_header_new(&head) {
+        let data_start = header.file_offsets[9] as usize + 6; //Skip <data>
+        if head.len() < data_start {
+            let n = head.len();
+            head.resize(data_start, 0);
+            r.read_exact(&mut head[n..]).unwrap();
+        }
+        let (metadata, _) = parse_head_new(&head).unwrap();
+        if can_stream(&metadata) {
+            let rest = head.split_off(data_start);
+            return Input::Stream(InputStream {
+                metadata,
+                reader: Box::new(Cursor::new(rest).chain(r)),
+            });
+        }
+    }
+    Input::Data(load(Cursor::new(head).chain(r), opts, true))
+}
+
+fn read_up_to(r: &mut impl Read, buf: &mut [u8]) -> usize {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]).unwrap() {
+            0 => break,
+            k => n += k,
+        }
+    }
+    n
+}
+
+fn map_file(f: &File) -> InputData {
+    let len = f.metadata().unwrap().len() as usize;
+    if len == 0 {
+        return InputData::Memory(Vec::new());
+    }
+    unsafe {
+        InputData::Mapped(MmapOptions::new(len).unwrap().with_file(f, 0).map().unwrap())
+    }
+}
+
+fn read_zip_member<R: Read + Seek>(archive: R, archive_name: &str, member: Option<&str>, opts: &InputOptions) -> InputData {
+    let mut zip = zip::ZipArchive::new(BufReader::new(archive)).unwrap();
+    let name = match member {
+        Some(m) => m.to_string(),
+        None => {
+            let files: Vec<&str> = zip.file_names().filter(|n| !n.ends_with('/')).collect();
+            if files.len() != 1 {
+                panic!(
+                    "{} holds {} files, name one as {}{}path/in/zip.dta",
+                    archive_name,
+                    files.len(),
+                    archive_name,
+                    ZIP_MEMBER_SEP
+                );
+            }
+            files[0].to_string()
+        }
+    };
+    let entry = zip
+        .by_name(&name)
+        .unwrap_or_else(|e| panic!("{}{}{}: {}", archive_name, ZIP_MEMBER_SEP, name, e));
+    load(entry, opts, opts.spill)
+}
+
+fn temp_file(opts: &InputOptions) -> File {
+    match &opts.temp_dir {
+        Some(d) => tempfile::tempfile_in(d),
+        None => tempfile::tempfile(),
+    }
+    .unwrap()
+}
+
+/// Read all of `r` into memory, or into a temporary file if `spill`
+fn load(mut r: impl Read, opts: &InputOptions, spill: bool) -> InputData {
+    if spill {
+        let mut tmp = temp_file(opts);
+        io::copy(&mut r, &mut tmp).unwrap();
+        tmp.flush().unwrap();
+        map_file(&tmp)
+    } else {
+        let mut v = Vec::new();
+        r.read_to_end(&mut v).unwrap();
+        InputData::Memory(v)
+    }
+}
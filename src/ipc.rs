@@ -0,0 +1,60 @@
+use std::io::Write;
+
+use arrow::datatypes::Schema;
+use arrow::ipc::writer::{FileWriter, IpcWriteOptions, StreamWriter};
+use arrow::ipc::CompressionType;
+use arrow_array::RecordBatch;
+
+use super::output::BatchSink;
+
+fn write_options(compression: Option<CompressionType>) -> IpcWriteOptions {
+    IpcWriteOptions::default()
+        .try_with_compression(compression)
+        .unwrap()
+}
+
+/// Arrow IPC file (Feather v2) writer
+pub struct IpcFileSink<W: Write + Send> {
+    writer: FileWriter<W>,
+}
+
+impl<W: Write + Send> IpcFileSink<W> {
+    pub fn new(out: W, schema: &Schema, compression: Option<CompressionType>) -> IpcFileSink<W> {
+        let writer = FileWriter::try_new_with_options(out, schema, write_options(compression)).unwrap();
+        IpcFileSink { writer }
+    }
+}
+
+impl<W: Write + Send> BatchSink for IpcFileSink<W> {
+    fn write(&mut self, batch: &RecordBatch) {
+        self.writer.write(batch).unwrap();
+    }
+
+    fn finish(self: Box<Self>) {
+        let mut out = self.writer.into_inner().unwrap();
+        out.flush().unwrap();
+    }
+}
+
+/// Arrow IPC stream writer
+pub struct IpcStreamSink<W: Write + Send> {
+    writer: StreamWriter<W>,
+}
+
+impl<W: Write + Send> IpcStreamSink<W> {
+    pub fn new(out: W, schema: &Schema, compression: Option<CompressionType>) -> IpcStreamSink<W> {
+        let writer = StreamWriter::try_new_with_options(out, schema, write_options(compression)).unwrap();
+        IpcStreamSink { writer }
+    }
+}
+
+impl<W: Write + Send> BatchSink for IpcStreamSink<W> {
+    fn write(&mut self, batch: &RecordBatch) {
+        self.writer.write(batch).unwrap();
+    }
+
+    fn finish(self: Box<Self>) {
+        let mut out = self.writer.into_inner().unwrap();
+        out.flush().unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arrow::array::{Int32Array, StringArray};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow_array::RecordBatch;
+
+use super::output::{check_overwrite, open_file_sink, OutputOptions, STDOUT};
+use super::stata::file::Metadata;
+use super::translate::Translation;
+
+/// The value label tables of a file in long format, a row per
+/// labelled value: `labelname`, `value`, `label`
+pub fn label_tables_batch(md: &Metadata) -> RecordBatch {
+    let rows = md.value_labels.iter().flat_map(|t| t.values.iter().zip(&t.labels).map(move |(v, l)| (t, *v, l)));
+    let (mut names, mut values, mut labels) = (Vec::new(), Vec::new(), Vec::new());
+    for (t, v, l) in rows {
+        names.push(t.labelname.as_str());
+        values.push(v);
+        labels.push(l.as_str());
+    }
+    let schema = Schema::new(vec![
+        Field::new("labelname", DataType::Utf8, false),
+        Field::new("value", DataType::Int32, false),
+        Field::new("label", DataType::Utf8, false),
+    ]);
+    RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            Arc::new(StringArray::from(names)),
+            Arc::new(Int32Array::from(values)),
+            Arc::new(StringArray::from(labels)),
+        ],
+    )
+    .unwrap()
+}
+
+/// The value label table of each written column that has one:
+/// `variable`, the output name of the column, and `labelname`
+pub fn label_map_batch(md: &Metadata, translation: &Translation) -> RecordBatch {
+    let (variables, names): (Vec<&str>, Vec<&str>) = md
+        .vars
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| !v.value_label.is_empty())
+        .filter_map(|(i, v)| Some((translation.out_name(i)?, v.value_label.as_str())))
+        .unzip();
+    let schema = Schema::new(vec![
+        Field::new("variable", DataType::Utf8, false),
+        Field::new("labelname", DataType::Utf8, false),
+    ]);
+    RecordBatch::try_new(
+        Arc::new(schema),
+        vec![Arc::new(StringArray::from(variables)), Arc::new(StringArray::from(names))],
+    )
+    .unwrap()
+}
+
+/// The paths of the label files of the output `out_path`:
+/// `out.labels.parquet` and `out.label_map.parquet` for `out.parquet`
+/// or the directory `out`, in the output format
+pub fn label_paths(out_path: &Path, opts: &OutputOptions) -> (PathBuf, PathBuf) {
+    if out_path.as_os_str() == STDOUT {
+        panic!("Label tables cannot be written with the output to standard output");
+    }
+    //A partitioned directory keeps its whole name
+    let stem = if opts.partition.is_some() { out_path.file_name() } else { out_path.file_stem() };
+    let stem = stem.unwrap().to_string_lossy();
+    let ext = opts.format.extension();
+    (
+        out_path.with_file_name(format!("{}.labels.{}", stem, ext)),
+        out_path.with_file_name(format!("{}.label_map.{}", stem, ext)),
+    )
+}
+
+/// Panic before converting if a label file of `out_path` exists and
+/// may not be overwritten
+pub fn check_label_tables(out_path: &Path, opts: &OutputOptions) {
+    let (tables_path, map_path) = label_paths(out_path, opts);
+    check_overwrite(&tables_path, opts.force);
+    check_overwrite(&map_path, opts.force);
+}
+
+/// Write the label files of the output `out_path`, as named by `label_paths`
+pub fn write_label_tables(out_path: &Path, md: &Metadata, translation: &Translation, opts: &OutputOptions) {
+    let (tables_path, map_path) = label_paths(out_path, opts);
+    let mut opts = opts.clone();
+    opts.partition = None;
+    opts.split = None;
+    opts.sort = None;
+    opts.parquet.columns.clear();
+    for (path, batch) in [(tables_path, label_tables_batch(md)), (map_path, label_map_batch(md, translation))] {
+        let mut sink = open_file_sink(&path, batch.schema(), &[], &opts);
+        sink.write(&batch);
+        sink.finish();
+    }
+}
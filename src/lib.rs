@@ -1,4 +1,23 @@
 pub mod stata;
+pub mod input;
 pub mod parquet;
+pub mod ipc;
+pub mod output;
+pub mod partition;
+pub mod split;
+pub mod text;
 pub mod translate;
-pub mod concurrency;
\ No newline at end of file
+pub mod rename;
+pub mod filter;
+pub mod select;
+pub mod sort;
+pub mod spec;
+pub mod describe;
+pub mod design;
+pub mod labels;
+pub mod datapackage;
+pub mod ddi;
+pub mod optimize;
+pub mod concurrency;
+pub mod batch;
+pub mod combine;
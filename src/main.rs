@@ -1,65 +1,522 @@
-use std::cmp::min;
+use std::any::Any;
+use std::cmp::{max, min};
+use std::collections::HashMap;
 use std::fs;
-use std::fs::File;
-use std::path::Path;
+use std::io::{self, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use mmap_rs::MmapOptions;
+use arrow::array::BooleanArray;
+use arrow::compute::filter_record_batch;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow_array::RecordBatch;
 
-use parquet::arrow::arrow_writer::ArrowLeafColumn;
-
-use dta2pqt::translate::make_schema;
-use dta2pqt::stata::file::{parse_data, parse_metadata, parse_strls};
-use dta2pqt::parquet::write_data;
+use dta2pqt::translate::{sorted_by_metadata, TranslateSink, Translation};
+use dta2pqt::stata::{Var, VarType};
+use dta2pqt::stata::file::{parse_data, parse_metadata, parse_strls, FileMap, Metadata, StrlEntry};
+use dta2pqt::filter::BoundFilter;
+use dta2pqt::select::RowSelection;
+use dta2pqt::sort::SortCheckSink;
+use dta2pqt::spec::starter_spec;
+use dta2pqt::describe::{characteristics_metadata, describe};
+use dta2pqt::design::design_metadata;
+use dta2pqt::labels::{check_label_tables, write_label_tables};
+use dta2pqt::datapackage::{check_descriptor, descriptor_path, DataPackage, LabelScanSink};
+use dta2pqt::ddi::{codebook, CodebookStats};
+use dta2pqt::optimize::{Narrowed, ScanSink, TypeScan};
+use dta2pqt::input::{is_rereadable, open_input, open_source, Input, InputOptions, InputStream};
+use dta2pqt::output::{check_overwrite, open_sink, write_file, BatchSink, OutputOptions};
+use dta2pqt::batch::{expand_inputs, render_template};
+use dta2pqt::combine::{unify_vars, AppendSink};
 use dta2pqt::concurrency::{seq_rw_marshall,Sender};
 
 pub mod cli;
-use crate::cli::Args;
+use crate::cli::{Args, BatchArgs, CombineArgs, Command, DdiArgs, DescribeArgs, SpecCommand, SpecInitArgs};
 use clap::Parser;
+use rayon::prelude::*;
 
 fn main() {
     let args = Args::parse();
-        dta2pqt(&args.infile,&args.outfile);
+    let threads = match &args.command {
+        Some(Command::Batch(b)) => b.opts.threads(),
+        Some(Command::Combine(c)) => c.opts.threads(),
+        Some(Command::Describe(_)) | Some(Command::Spec(_)) => 1,
+        Some(Command::Ddi(_)) => usize::from(thread::available_parallelism().unwrap()),
+        None => args.opts.threads(),
+    };
+    //Encoding and text rendering share this pool
+    rayon::ThreadPoolBuilder::new().num_threads(threads).build_global().unwrap();
+    match &args.command {
+        Some(Command::Batch(b)) => {
+            if !batch(b, threads) {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Combine(c)) => combine(c, threads),
+        Some(Command::Describe(a)) => describe_file(a),
+        Some(Command::Ddi(a)) => ddi(a),
+        Some(Command::Spec(SpecCommand::Init(a))) => spec_init(a),
+        None => {
+            let out_path = args.outfile.as_ref().unwrap();
+            let mut opts = args.opts.output_options(out_path);
+            if let Some(c) = args.compression {
+                opts.parquet.compression = c;
+            }
+            let in_opts = args.opts.input_options();
+            let select = args.opts.row_selection();
+            let narrowed = dta2pqt(args.infile.as_ref().unwrap(),out_path,&in_opts,&opts,&select,threads);
+            report_narrowed(&narrowed, "");
+        }
+    }
+}
+
+/// How a file of a batch went: the time taken and the columns narrowed
+/// by `--optimize`, or why it failed
+type Outcome = Result<(Duration, Vec<Narrowed>), String>;
+
+/// Convert the files of a batch, `args.jobs` at a time, and print a
+/// summary. Returns whether all files were converted.
+fn batch(args: &BatchArgs, threads: usize) -> bool {
+    let inputs = expand_inputs(&args.inputs);
+    let outputs: Vec<PathBuf> = inputs.iter().map(|i| render_template(&args.output, i)).collect();
+    let mut seen = HashMap::new();
+    for (i, o) in inputs.iter().zip(&outputs) {
+        if let Some(prev) = seen.insert(o, &i.path) {
+            panic!("{} and {} would both be written to {}", prev.display(), i.path.display(), o.display());
+        }
+    }
+    if args.opts.datapackage {
+        let mut described = HashMap::new();
+        for (i, o) in inputs.iter().zip(&outputs) {
+            if let Some(prev) = described.insert(descriptor_path(o), &i.path) {
+                panic!(
+                    "{} and {} would both be described by {}, as each output needs a directory of its own with --datapackage",
+                    prev.display(),
+                    i.path.display(),
+                    descriptor_path(o).display()
+                );
+            }
+        }
+    }
+    let jobs = args.jobs.unwrap_or(threads / 4).clamp(1, inputs.len());
+    //Chunks in flight per file
+    let inflight = max(1, threads / jobs);
+    let in_opts = args.opts.input_options();
+    let select = args.opts.row_selection();
+
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let results: Vec<Mutex<Option<Outcome>>> =
+        inputs.iter().map(|_| Mutex::new(None)).collect();
+    thread::scope(|ts| {
+        for _ in 0..jobs {
+            ts.spawn(|| loop {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= inputs.len() {
+                    break;
+                }
+                let (in_path, out_path) = (&inputs[i].path, &outputs[i]);
+                let started = Instant::now();
+                let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                    if let Some(dir) = out_path.parent() {
+                        if !dir.as_os_str().is_empty() {
+                            fs::create_dir_all(dir).unwrap_or_else(|e| panic!("{}: {}", dir.display(), e));
+                        }
+                    }
+                    let opts = args.opts.output_options(out_path);
+                    dta2pqt(in_path, out_path, &in_opts, &opts, &select, inflight)
+                }));
+                let res = res.map(|n| (started.elapsed(), n)).map_err(|e| panic_message(&*e));
+                if res.is_err() && !args.keep_going {
+                    stop.store(true, Ordering::SeqCst);
+                }
+                *results[i].lock().unwrap() = Some(res);
+            });
+        }
+    });
+
+    let (mut ok, mut failed, mut skipped) = (0, 0, 0);
+    for ((input, out_path), res) in inputs.iter().zip(&outputs).zip(results) {
+        match res.into_inner().unwrap() {
+            Some(Ok((t, narrowed))) => {
+                ok += 1;
+                eprintln!("ok      {} -> {} ({:.2}s)", input.path.display(), out_path.display(), t.as_secs_f64());
+                report_narrowed(&narrowed, "        ");
+            }
+            Some(Err(msg)) => {
+                failed += 1;
+                eprintln!("FAILED  {}: {}", input.path.display(), msg);
+            }
+            None => {
+                skipped += 1;
+                eprintln!("skipped {}", input.path.display());
+            }
+        }
+    }
+    eprintln!("{} files: {} converted, {} failed, {} skipped", inputs.len(), ok, failed, skipped);
+    failed == 0 && skipped == 0
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "conversion failed".to_string()
+    }
+}
+
+/// Convert `in_path` to `out_path`. Returns the columns narrowed
+/// by `opts.optimize`.
+fn dta2pqt(in_path: &Path, out_path: &Path, in_opts: &InputOptions, opts: &OutputOptions, select: &RowSelection, max_inflight: usize) -> Vec<Narrowed> {
+    if opts.optimize {
+        if !is_rereadable(in_path) {
+            panic!("{}: only regular files can be optimized, as the input is read twice", in_path.display());
+        }
+        let mut scan = None;
+        decode(in_path, in_opts, opts, select, max_inflight, |metadata, translation| {
+            let scan = scan.insert(TypeScan::new(&metadata.vars, &translation));
+            let schema = Arc::new(translation.schema().clone());
+            Box::new(TranslateSink::new(Box::new(ScanSink::new(scan)), translation, schema))
+        });
+        let scan = scan.unwrap();
+        //The spec's own types take precedence
+        let mut opts = opts.clone();
+        opts.optimize = false;
+        opts.variables = [scan.rules(), opts.variables].concat();
+        dta2pqt(in_path, out_path, in_opts, &opts, select, max_inflight);
+        return scan.narrowed();
+    }
+    if opts.label_tables {
+        check_label_tables(out_path, opts);
+    }
+    if opts.datapackage {
+        check_descriptor(out_path, opts.force);
+    }
+    let mut package = None;
+    decode(in_path, in_opts, opts, select, max_inflight, |metadata, translation| {
+        let mut schema_metadata = characteristics_metadata(&metadata.characteristics);
+        //Variables of the design are named as their columns, where written
+        schema_metadata.extend(design_metadata(metadata, |name| {
+            let i = metadata.vars.iter().position(|v| v.name == name);
+            i.and_then(|i| translation.out_name(i)).unwrap_or(name).to_string()
+        }));
+        let columns: Vec<&str> = metadata.sortlist.iter().map_while(|&i| translation.sorted_name(i)).collect();
+        if !columns.is_empty() {
+            schema_metadata.extend(sorted_by_metadata(&columns));
+        }
+        let schema = Arc::new(translation.schema().clone().with_metadata(schema_metadata));
+        if opts.label_tables {
+            write_label_tables(out_path, metadata, &translation, opts);
+        }
+        let mut opts = opts.clone();
+        translation.column_options(&mut opts.parquet);
+        let mut sink = open_sink(out_path, schema.clone(), translation.out_vars(), &opts);
+        if opts.verify_sort {
+            sink = Box::new(SortCheckSink::new(sink, schema.clone(), in_path.display().to_string()));
+        }
+        let sink: Box<dyn BatchSink + '_> = if opts.datapackage {
+            let package = package.insert(DataPackage::new(out_path, metadata, &translation, &schema, &opts));
+            Box::new(LabelScanSink::new(sink, package))
+        } else {
+            sink
+        };
+        Box::new(TranslateSink::new(sink, translation, schema))
+    });
+    //The package lists the files written, so comes last
+    if let Some(p) = package {
+        p.write(out_path, opts);
+    }
+    Vec::new()
+}
+
+/// Print the columns narrowed by `--optimize`
+fn report_narrowed(narrowed: &[Narrowed], indent: &str) {
+    if !narrowed.is_empty() {
+        eprintln!("{}Optimized column types:", indent);
+    }
+    for n in narrowed {
+        eprintln!("{}  {}", indent, n);
+    }
 }
 
-fn dta2pqt(in_path: &Path, out_path: &Path) {
-    let infile = File::open(in_path).unwrap();
-    let infile_len = fs::metadata(in_path).unwrap().len();
-    unsafe {
-        let inmap = MmapOptions::new(infile_len.try_into().unwrap())
-            .unwrap()
-            .with_file(&infile, 0)
-            .map()
-            .unwrap();
-
-        let res = parse_metadata(&inmap);
-        if let Err(e) = res {
-            panic!("{:?}",e)
+/// Append the files of `args.inputs` into one output, with their
+/// variables unified by `unify_vars`
+fn combine(args: &CombineArgs, threads: usize) {
+    let inputs = expand_inputs(&args.inputs);
+    let in_opts = args.opts.input_options();
+    let mut opts = args.opts.output_options(&args.output);
+    if opts.label_tables || opts.datapackage {
+        panic!("Label tables and data packages cannot be written when combining files");
+    }
+    let select = args.opts.row_selection();
+    //The files are read twice, first for their variables
+    let files: Vec<(PathBuf, Vec<Var>)> = inputs
+        .iter()
+        .map(|i| {
+            if !is_rereadable(&i.path) {
+                panic!("{}: only regular files can be combined, as the inputs are read twice", i.path.display());
+            }
+            let input = open_input(&i.path, &in_opts);
+            let (metadata, _) = parse_metadata(&input).unwrap_or_else(|e| panic!("{}: {:?}", i.path.display(), e));
+            (i.path.clone(), metadata.vars)
+        })
+        .collect();
+    let vars = unify_vars(&files);
+    let mut narrowed = Vec::new();
+    if opts.optimize {
+        let translation = Translation::new(&vars, &opts.variables, &opts.names, opts.widening, opts.missing_codes());
+        let mut scan = TypeScan::new(&vars, &translation);
+        let parsed = Arc::new(translation.parsed_schema());
+        let schema = Arc::new(translation.schema().clone());
+        let mut sink = TranslateSink::new(Box::new(ScanSink::new(&mut scan)), translation, schema);
+        for input in &inputs {
+            decode(&input.path, &in_opts, &opts, &select, threads, |_, _| {
+                Box::new(AppendSink::new(&mut sink, parsed.clone(), None))
+            });
         }
-        let (metadata,file_map) = res.unwrap();
+        drop(sink);
+        opts.variables = [scan.rules(), opts.variables].concat();
+        narrowed = scan.narrowed();
+    }
+    let translation = Translation::new(&vars, &opts.variables, &opts.names, opts.widening, opts.missing_codes());
+    //Batches are conformed to the parsed layout of the unified variables,
+    //and the source column passed through the translation
+    let mut schema = translation.schema().clone();
+    let mut parsed = translation.parsed_schema();
+    if let Some(name) = &args.source_column {
+        if schema.index_of(name).is_ok() {
+            panic!("{} is already a variable, choose another name for the source column", name);
+        }
+        schema = with_source_column(&schema, name);
+        parsed = with_source_column(&parsed, name);
+    }
+    let (schema, parsed) = (Arc::new(schema), Arc::new(parsed));
+    translation.column_options(&mut opts.parquet);
+    let sink = open_sink(&args.output, schema.clone(), translation.out_vars(), &opts);
+    let mut sink = Box::new(TranslateSink::new(sink, translation, schema));
+    for input in &inputs {
+        let source = args.source_column.as_deref().map(|c| (c, input.path.display().to_string()));
+        decode(&input.path, &in_opts, &opts, &select, threads, |_, _| {
+            Box::new(AppendSink::new(&mut *sink, parsed.clone(), source))
+        });
+    }
+    sink.finish();
+    report_narrowed(&narrowed, "");
+}
+
+/// Print the variables, notes and characteristics of `args.input`
+fn describe_file(args: &DescribeArgs) {
+    let input = open_input(&args.input, &InputOptions::default());
+    let (metadata, _) = parse_metadata(&input).unwrap_or_else(|e| panic!("{}: {:?}", args.input.display(), e));
+    print!("{}", describe(&args.input.display().to_string(), &metadata));
+}
+
+/// Write a DDI Codebook for `args.input`, with summary statistics
+/// from a pass over the data if asked for
+fn ddi(args: &DdiArgs) {
+    if let Some(path) = &args.output {
+        check_overwrite(path, args.force);
+    }
+    let input = open_input(&args.input, &InputOptions::default());
+    let (metadata, file_map) = parse_metadata(&input).unwrap_or_else(|e| panic!("{}: {:?}", args.input.display(), e));
+    let stats = args.stats.then(|| {
         let strl_tab = parse_strls(file_map.strls_buf).unwrap();
-        //println!("{:?}",metadata);
-        let schema = make_schema(&metadata.vars);
-        let mut m = 0;
-        let mut tasks = Vec::new();
-        while m < metadata.nobs {
-            let n = min(m+10000,metadata.nobs);
+        let parsed = vec![true; metadata.nvars];
+        (0..metadata.nobs.div_ceil(10000))
+            .into_par_iter()
+            .map(|c| {
+                let (m, n) = (c * 10000, min((c + 1) * 10000, metadata.nobs));
+                let d = parse_data(&metadata, &file_map, &strl_tab, m, n, true, &parsed).unwrap();
+                let mut stats = CodebookStats::new(&metadata);
+                stats.update(&metadata, &d);
+                stats
+            })
+            .reduce(|| CodebookStats::new(&metadata), CodebookStats::merge)
+    });
+    let name = args.input.file_name().map_or_else(|| args.input.display().to_string(), |n| n.to_string_lossy().into_owned());
+    let xml = codebook(&name, &metadata, stats.as_ref());
+    match &args.output {
+        Some(path) => write_file(path, xml.as_bytes(), args.force),
+        None => print!("{}", xml),
+    }
+}
+
+/// Write a starter spec for the variables of `args.input`
+fn spec_init(args: &SpecInitArgs) {
+    let input = open_input(&args.input, &InputOptions::default());
+    let (metadata, _) = parse_metadata(&input).unwrap_or_else(|e| panic!("{}: {:?}", args.input.display(), e));
+    let spec = starter_spec(&args.input.display().to_string(), &metadata.vars);
+    match &args.output {
+        Some(path) => write_file(path, spec.as_bytes(), args.force),
+        None => print!("{}", spec),
+    }
+}
+
+fn with_source_column(schema: &Schema, name: &str) -> Schema {
+    let mut fields: Vec<Field> = schema.fields().iter().map(|f| f.as_ref().clone()).collect();
+    fields.push(Field::new(name, DataType::Utf8, true));
+    Schema::new(fields)
+}
+
+/// Parse the data of `in_path` in chunks, at most `max_inflight` at a time,
+/// and write the rows picked by `select` to the sink that `open` makes from
+/// the file's metadata and its `Translation` by `opts.variables`. Chunks
+/// without picked rows are not parsed, nor are variables that are neither
+/// written nor filtered on.
+fn decode<'s, F>(in_path: &Path, in_opts: &InputOptions, opts: &OutputOptions, select: &RowSelection, max_inflight: usize, open: F)
+where
+    F: FnOnce(&Metadata, Translation) -> Box<dyn BatchSink + 's>,
+{
+    let translate = |md: &Metadata| Translation::new(&md.vars, &opts.variables, &opts.names, opts.widening, opts.missing_codes());
+    //Value labels and strLs are stored after the data
+    let can_stream = |md: &Metadata| {
+        !opts.needs_value_labels()
+            && !translate(md).needs_value_labels()
+            && !select.filter.as_ref().is_some_and(|f| f.uses_value_labels())
+            && !md.vars.iter().any(|v| matches!(v.ty, VarType::TStrl))
+    };
+    match open_source(in_path, in_opts, can_stream) {
+        Input::Data(input) => {
+            let res = parse_metadata(&input);
+            if let Err(e) = res {
+                panic!("{:?}",e)
+            }
+            let (metadata,file_map) = res.unwrap();
+            let strl_tab = parse_strls(file_map.strls_buf).unwrap();
+            let translation = translate(&metadata);
+            let missing_codes = opts.missing_codes() || translation.needs_missing_codes();
+            let parsed = parsed_vars(&metadata, &translation, select);
+            //println!("{:?}",metadata);
+            let bound = select.filter.as_ref().map(|f| f.bind(&metadata.vars, &metadata.value_labels));
+            let picked = select.pick(metadata.nobs);
+            let range = picked.range();
+            let mut m = range.start;
+            let mut tasks = Vec::new();
+            while m < range.end {
+                let n = min(m+10000,range.end);
+                if picked.any(m, n) {
+                    let md = &metadata;
+                    let fm = &file_map;
+                    let st = &strl_tab;
+                    let bf = bound.as_ref();
+                    let pv = &parsed;
+                    let pk = &picked;
+                    tasks.push(move |s:Sender<RecordBatch>| {
+                        move || {
+                            let mask = pk.mask(m, n);
+                            let d = parse_chunk(md,fm,st,m,n,missing_codes,pv,mask,bf);
+                            s.send(d).unwrap();
+                        }
+                    });
+                }
+                m = n;
+            }
+            convert(&metadata, translation, &mut tasks.into_iter(), open, max_inflight);
+        }
+        Input::Stream(InputStream { metadata, mut reader }) => {
+            let translation = translate(&metadata);
+            let missing_codes = opts.missing_codes() || translation.needs_missing_codes();
+            let parsed = parsed_vars(&metadata, &translation, select);
+            let pv = &parsed;
+            let bound = select.filter.as_ref().map(|f| f.bind(&metadata.vars, &metadata.value_labels));
+            let bf = bound.as_ref();
+            let picked = select.pick(metadata.nobs);
+            let pk = &picked;
+            let range = picked.range();
             let md = &metadata;
-            let fm = &file_map;
-            let st = &strl_tab;
-            tasks.push(move |s:Sender<Vec<ArrowLeafColumn>>| {
-                move || {
-                    let d = parse_data(md,fm,st,m,n).unwrap();
-                    s.send(d).unwrap();
+            io::copy(&mut (&mut reader).take((range.start*md.rowsize) as u64), &mut io::sink()).unwrap();
+            let mut m = range.start;
+            let mut tasks = std::iter::from_fn(|| loop {
+                if m >= range.end {
+                    return None;
                 }
+                let n = min(m+10000,range.end);
+                let mut chunk = vec![0u8; (n-m)*md.rowsize];
+                reader.read_exact(&mut chunk).unwrap();
+                let (start, rows) = (m, n-m);
+                m = n;
+                if !picked.any(start, n) {
+                    continue;
+                }
+                return Some(move |s:Sender<RecordBatch>| {
+                    move || {
+                        let mask = pk.mask(start, n);
+                        let fm = FileMap { data_buf: &chunk, value_labels_buf: &[], strls_buf: &[] };
+                        let d = parse_chunk(md,&fm,&Vec::new(),0,rows,missing_codes,pv,mask,bf);
+                        s.send(d).unwrap();
+                    }
+                });
             });
-            m = n;
+            convert(&metadata, translation, &mut tasks, open, max_inflight);
+            //Drain the rest so that the writing end of the pipe does not fail
+            io::copy(&mut reader, &mut io::sink()).unwrap();
         }
-        let mut arrays:Vec<Vec<ArrowLeafColumn>> = Vec::new();
-        let mut pusher = |d| {arrays.push(d);};
-        let par_avail = usize::from(std::thread::available_parallelism().unwrap());
-        seq_rw_marshall(&mut tasks.into_iter(),
-                        &mut pusher,
-                        par_avail);
-        write_data(out_path,arrays, &schema);
     }
 }
+
+/// Parse rows `start_row..end_row` of `file_map`, keeping those
+/// picked by `mask` and passing `filter`
+#[allow(clippy::too_many_arguments)]
+fn parse_chunk(
+    md: &Metadata,
+    file_map: &FileMap,
+    strl_tab: &Vec<StrlEntry>,
+    start_row: usize,
+    end_row: usize,
+    missing_codes: bool,
+    parsed: &[bool],
+    mask: Option<BooleanArray>,
+    filter: Option<&BoundFilter>,
+) -> RecordBatch {
+    //The filter tells extended missing values apart by their codes
+    let mut d = parse_data(md, file_map, strl_tab, start_row, end_row, missing_codes || filter.is_some(), parsed).unwrap();
+    if let Some(mask) = mask {
+        d = filter_record_batch(&d, &mask).unwrap();
+    }
+    match filter {
+        None => d,
+        Some(f) => {
+            let d = f.filter(&d);
+            if missing_codes {
+                d
+            } else {
+                d.project(&(0..md.nvars).collect::<Vec<_>>()).unwrap()
+            }
+        }
+    }
+}
+
+/// Which variables of the file are parsed: those written
+/// and those the filter uses
+fn parsed_vars(md: &Metadata, translation: &Translation, select: &RowSelection) -> Vec<bool> {
+    md.vars
+        .iter()
+        .zip(translation.specs())
+        .map(|(v, s)| s.include || select.filter.as_ref().is_some_and(|f| f.uses_variable(&v.name)))
+        .collect()
+}
+
+/// Run the chunk parsing `tasks` and write their output
+fn convert<'s, I, T, F>(metadata: &Metadata, translation: Translation, tasks: &mut I, open: F, max_inflight: usize)
+where
+    I: Iterator,
+    I::Item: FnOnce(Sender<RecordBatch>) -> T,
+    T: FnOnce() + Send,
+    F: FnOnce(&Metadata, Translation) -> Box<dyn BatchSink + 's>,
+{
+    let mut sink = open(metadata, translation);
+    let mut pusher = |d: RecordBatch| {sink.write(&d);};
+    seq_rw_marshall(tasks,
+                    &mut pusher,
+                    max_inflight);
+    sink.finish();
+}
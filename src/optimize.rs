@@ -0,0 +1,195 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use arrow::array::{ArrayRef, AsArray};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Float64Type};
+use arrow_array::RecordBatch;
+use rayon::prelude::*;
+
+use super::output::BatchSink;
+use super::stata::Var;
+use super::translate::{Translation, VariableRule};
+
+/// Most distinct values of a string column given a dictionary
+const MAX_DICTIONARY: usize = i16::MAX as usize + 1;
+
+/// A column whose type `--optimize` narrowed
+#[derive(Debug, Clone)]
+pub struct Narrowed {
+    pub column: String,
+    pub from: DataType,
+    pub to: DataType,
+}
+
+impl fmt::Display for Narrowed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.column, self.from, self.to)
+    }
+}
+
+/// The narrowest lossless types of the output columns of a `Translation`,
+/// found by scanning the batches it writes, as Stata's `compress` does
+///
+/// Integral numbers become the smallest integer type holding them, other
+/// doubles that round-trip through `f32` become `Float32`, and strings with
+/// few distinct values become dictionaries. Columns the translation casts
+/// to a type of its own are left alone.
+pub struct TypeScan {
+    columns: Vec<ColumnScan>,
+}
+
+struct ColumnScan {
+    /// Index of the output column
+    index: usize,
+    /// Name of the variable, which rules match
+    var: String,
+    name: String,
+    from: DataType,
+    min: f64,
+    max: f64,
+    integral: bool,
+    /// Whether all values round-trip through `f32`
+    single: bool,
+    /// Distinct strings, until there are too many
+    distinct: Option<HashSet<String>>,
+}
+
+impl TypeScan {
+    /// Scan the output of `translation` for the variables `vars`
+    pub fn new(vars: &[Var], translation: &Translation) -> TypeScan {
+        let schema = translation.schema();
+        let columns = translation
+            .sources()
+            .iter()
+            .enumerate()
+            .filter(|&(_, &i)| translation.specs()[i].out_type.is_none())
+            .map(|(index, &i)| {
+                let f = schema.field(index);
+                ColumnScan {
+                    index,
+                    var: vars[i].name.clone(),
+                    name: f.name().clone(),
+                    from: f.data_type().clone(),
+                    min: f64::INFINITY,
+                    max: f64::NEG_INFINITY,
+                    integral: true,
+                    single: true,
+                    distinct: Some(HashSet::new()),
+                }
+            })
+            .collect();
+        TypeScan { columns }
+    }
+
+    pub fn update(&mut self, batch: &RecordBatch) {
+        self.columns.par_iter_mut().for_each(|c| c.update(batch.column(c.index)));
+    }
+
+    /// The columns given a narrower type
+    pub fn narrowed(&self) -> Vec<Narrowed> {
+        self.columns
+            .iter()
+            .filter_map(|c| {
+                c.narrowest().map(|to| Narrowed { column: c.name.clone(), from: c.from.clone(), to })
+            })
+            .collect()
+    }
+
+    /// Rules casting the variables of the narrowed columns to their
+    /// narrower types
+    pub fn rules(&self) -> Vec<VariableRule> {
+        self.columns
+            .iter()
+            .filter_map(|c| {
+                c.narrowest().map(|to| VariableRule {
+                    pattern: glob::Pattern::new(&glob::Pattern::escape(&c.var)).unwrap(),
+                    data_type: Some(to),
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+}
+
+impl ColumnScan {
+    fn update(&mut self, a: &ArrayRef) {
+        match a.data_type() {
+            DataType::Utf8 => {
+                let Some(distinct) = &mut self.distinct else {
+                    return;
+                };
+                for s in a.as_string::<i32>().iter().flatten() {
+                    if !distinct.contains(s) {
+                        if distinct.len() == MAX_DICTIONARY {
+                            self.distinct = None;
+                            return;
+                        }
+                        distinct.insert(s.to_string());
+                    }
+                }
+            }
+            t if t.is_numeric() => {
+                let x = cast(a, &DataType::Float64).unwrap();
+                for v in x.as_primitive::<Float64Type>().iter().flatten() {
+                    self.min = self.min.min(v);
+                    self.max = self.max.max(v);
+                    self.integral &= v.fract() == 0.0;
+                    self.single &= v as f32 as f64 == v;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn narrowest(&self) -> Option<DataType> {
+        let to = match &self.from {
+            DataType::Utf8 => {
+                let n = self.distinct.as_ref()?.len();
+                let key = if n <= i8::MAX as usize + 1 { DataType::Int8 } else { DataType::Int16 };
+                DataType::Dictionary(Box::new(key), Box::new(DataType::Utf8))
+            }
+            t if t.is_numeric() => {
+                let int = [
+                    (DataType::Int8, i8::MIN as f64, i8::MAX as f64),
+                    (DataType::Int16, i16::MIN as f64, i16::MAX as f64),
+                    (DataType::Int32, i32::MIN as f64, i32::MAX as f64),
+                ]
+                .into_iter()
+                .find(|(_, lo, hi)| self.min > self.max || (*lo <= self.min && self.max <= *hi))
+                .map(|(t, _, _)| t)
+                .filter(|_| self.integral);
+                match int {
+                    Some(t) => t,
+                    None if self.single => DataType::Float32,
+                    None => return None,
+                }
+            }
+            _ => return None,
+        };
+        let narrower = match (to.primitive_width(), self.from.primitive_width()) {
+            (Some(a), Some(b)) => a <= b,
+            _ => true,
+        };
+        Some(to).filter(|to| narrower && *to != self.from)
+    }
+}
+
+/// Feeds batches to a `TypeScan` and writes nothing
+pub struct ScanSink<'a> {
+    scan: &'a mut TypeScan,
+}
+
+impl<'a> ScanSink<'a> {
+    pub fn new(scan: &'a mut TypeScan) -> ScanSink<'a> {
+        ScanSink { scan }
+    }
+}
+
+impl BatchSink for ScanSink<'_> {
+    fn write(&mut self, batch: &RecordBatch) {
+        self.scan.update(batch);
+    }
+
+    fn finish(self: Box<Self>) {}
+}
@@ -0,0 +1,262 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use arrow::datatypes::SchemaRef;
+use arrow::ipc::CompressionType;
+use arrow_array::RecordBatch;
+use tempfile::{NamedTempFile, TempPath};
+
+use super::ipc::{IpcFileSink, IpcStreamSink};
+use super::parquet::{ParquetOptions, ParquetSink};
+use super::partition::{PartitionOptions, PartitionedSink};
+use super::sort::{SortOptions, SortingSink};
+use super::split::{SplitOptions, SplitSink};
+use super::stata::Var;
+use super::rename::NameTransform;
+use super::text::{LabelStyle, MissingStyle, TextLayout, TextOptions, TextSink};
+use super::translate::{VariableRule, Widening};
+
+/// The output path standing for standard output
+pub const STDOUT: &str = "-";
+
+/// A consumer of decoded record batches
+///
+/// Batches are passed in row order. `finish` must be called
+/// after the last batch to finalise the output.
+pub trait BatchSink: Send {
+    fn write(&mut self, batch: &RecordBatch);
+    fn finish(self: Box<Self>);
+
+    /// Bytes written out so far, if known
+    fn bytes_written(&self) -> u64 {
+        0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Parquet,
+    IpcFile,
+    IpcStream,
+    Csv,
+    Tsv,
+    NdJson,
+}
+
+impl OutputFormat {
+    pub fn from_name(s: &str) -> Option<OutputFormat> {
+        match s.to_ascii_lowercase().as_str() {
+            "parquet" | "pqt" => Some(OutputFormat::Parquet),
+            "ipc" | "arrow" | "feather" => Some(OutputFormat::IpcFile),
+            "arrows" | "ipc-stream" | "arrow-stream" => Some(OutputFormat::IpcStream),
+            "csv" => Some(OutputFormat::Csv),
+            "tsv" | "tab" => Some(OutputFormat::Tsv),
+            "ndjson" | "jsonl" | "json" => Some(OutputFormat::NdJson),
+            _ => None,
+        }
+    }
+
+    /// Guess the format from the extension of the output path.
+    /// Unknown extensions give parquet.
+    pub fn from_path(p: &Path) -> OutputFormat {
+        p.extension()
+            .and_then(|e| e.to_str())
+            .and_then(OutputFormat::from_name)
+            .unwrap_or(OutputFormat::Parquet)
+    }
+
+    /// The usual file extension of the format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Parquet => "parquet",
+            OutputFormat::IpcFile => "arrow",
+            OutputFormat::IpcStream => "arrows",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Tsv => "tsv",
+            OutputFormat::NdJson => "ndjson",
+        }
+    }
+
+    pub fn is_text(&self) -> bool {
+        matches!(self, OutputFormat::Csv | OutputFormat::Tsv | OutputFormat::NdJson)
+    }
+}
+
+#[derive(Clone)]
+pub struct OutputOptions {
+    pub format: OutputFormat,
+    pub ipc_compression: Option<CompressionType>,
+    pub text: TextOptions,
+    /// Overwrite existing output files
+    pub force: bool,
+    /// Write a directory tree partitioned by some columns
+    pub partition: Option<PartitionOptions>,
+    /// Split the output into files of bounded size
+    pub split: Option<SplitOptions>,
+    /// Warn if the rows are not sorted as the input claims
+    pub verify_sort: bool,
+    /// Narrow the column types by a first pass over the data
+    pub optimize: bool,
+    /// Sort the rows by some columns
+    pub sort: Option<SortOptions>,
+    pub parquet: ParquetOptions,
+    /// How to write each variable, see `Translation`
+    pub variables: Vec<VariableRule>,
+    /// How to name the columns
+    pub names: NameTransform,
+    pub widening: Widening,
+    /// Also write the value label tables and the table of each
+    /// variable, see `write_label_tables`
+    pub label_tables: bool,
+    /// Also write a `datapackage.json` describing the output
+    pub datapackage: bool,
+}
+
+impl OutputOptions {
+    /// Whether the sink needs the missing code columns
+    /// of `make_schema_with_missing_codes`
+    pub fn missing_codes(&self) -> bool {
+        self.format.is_text() && self.text.missing == MissingStyle::Stata
+    }
+
+    /// Whether the output needs the value label tables of the file
+    pub fn needs_value_labels(&self) -> bool {
+        self.label_tables
+            || self.datapackage
+            || self.partition.as_ref().is_some_and(|p| p.labels)
+            || (self.format.is_text() && self.text.labels == LabelStyle::Text)
+    }
+}
+
+/// Open a sink writing to `out_path`
+///
+/// With `opts.partition`, `out_path` is the root directory of a
+/// `PartitionedSink`, and with `opts.split` the base name of the files of
+/// a `SplitSink`. Otherwise it is the file opened by `open_file_sink`.
+pub fn open_sink(out_path: &Path, schema: SchemaRef, vars: &[Var], opts: &OutputOptions) -> Box<dyn BatchSink> {
+    if let Some(sort) = &opts.sort {
+        let mut inner_opts = opts.clone();
+        inner_opts.sort = None;
+        return Box::new(SortingSink::new(schema, sort, |schema| {
+            open_sink(out_path, schema, vars, &inner_opts)
+        }));
+    }
+    if opts.partition.is_some() {
+        return Box::new(PartitionedSink::new(out_path, schema, vars, opts));
+    }
+    if opts.split.is_some() {
+        return Box::new(SplitSink::new(out_path, schema, vars, opts));
+    }
+    open_file_sink(out_path, schema, vars, opts)
+}
+
+/// Open a sink writing to the file `out_path`
+///
+/// Output goes to a temporary file in the same directory, which is
+/// renamed to `out_path` when the sink is finished. An existing
+/// `out_path` is only replaced if `opts.force` is set. `-` writes
+/// to standard output.
+pub fn open_file_sink(out_path: &Path, schema: SchemaRef, vars: &[Var], opts: &OutputOptions) -> Box<dyn BatchSink> {
+    if out_path.as_os_str() == STDOUT {
+        return make_sink(BufWriter::new(io::stdout()), schema, vars, opts);
+    }
+    check_overwrite(out_path, opts.force);
+    let (file, path) = temp_file_beside(out_path).into_parts();
+    let inner = make_sink(BufWriter::new(file.try_clone().unwrap()), schema, vars, opts);
+    Box::new(RenamingSink {
+        inner,
+        file,
+        tmp_path: path,
+        out_path: out_path.to_path_buf(),
+        force: opts.force,
+    })
+}
+
+/// Write `contents` to the file `out_path` through a temporary file,
+/// as `open_file_sink` does
+pub fn write_file(out_path: &Path, contents: &[u8], force: bool) {
+    check_overwrite(out_path, force);
+    let mut tmp = temp_file_beside(out_path);
+    tmp.write_all(contents).unwrap();
+    tmp.as_file().sync_all().unwrap();
+    let res = if force { tmp.persist(out_path) } else { tmp.persist_noclobber(out_path) };
+    if let Err(e) = res {
+        panic!("{}: {}", out_path.display(), e.error);
+    }
+}
+
+/// A temporary file in the directory of `out_path`, hidden and named after it
+fn temp_file_beside(out_path: &Path) -> NamedTempFile {
+    let file_name = out_path.file_name().unwrap().to_string_lossy();
+    let dir = match out_path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let prefix = format!(".{}.", file_name);
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(&prefix).suffix(".tmp");
+    #[cfg(unix)]
+    {
+        //Leave the permissions to the umask, as for a file created directly
+        use std::os::unix::fs::PermissionsExt;
+        builder.permissions(std::fs::Permissions::from_mode(0o666));
+    }
+    builder
+        .tempfile_in(dir)
+        .unwrap_or_else(|e| panic!("{}: {}", dir.display(), e))
+}
+
+/// Panic if `out_path` exists and may not be overwritten
+pub fn check_overwrite(out_path: &Path, force: bool) {
+    if !force && out_path.exists() {
+        panic!("{} exists, use --force to overwrite it", out_path.display());
+    }
+}
+
+fn make_sink<W: Write + Send + 'static>(of: W, schema: SchemaRef, vars: &[Var], opts: &OutputOptions) -> Box<dyn BatchSink> {
+    match opts.format {
+        OutputFormat::Parquet => Box::new(ParquetSink::new(of, schema, &opts.parquet)),
+        OutputFormat::IpcFile => Box::new(IpcFileSink::new(of, &schema, opts.ipc_compression)),
+        OutputFormat::IpcStream => Box::new(IpcStreamSink::new(of, &schema, opts.ipc_compression)),
+        OutputFormat::Csv | OutputFormat::Tsv => {
+            Box::new(TextSink::new(of, TextLayout::Delimited, &schema, vars, opts.text.clone()))
+        }
+        OutputFormat::NdJson => {
+            Box::new(TextSink::new(of, TextLayout::NdJson, &schema, vars, opts.text.clone()))
+        }
+    }
+}
+
+/// Writes through `inner` to a temporary file, renamed to `out_path` by `finish`.
+/// The temporary file is removed if the sink is dropped unfinished.
+struct RenamingSink {
+    inner: Box<dyn BatchSink>,
+    file: File,
+    tmp_path: TempPath,
+    out_path: PathBuf,
+    force: bool,
+}
+
+impl BatchSink for RenamingSink {
+    fn write(&mut self, batch: &RecordBatch) {
+        self.inner.write(batch);
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.file.metadata().unwrap().len()
+    }
+
+    fn finish(self: Box<Self>) {
+        self.inner.finish();
+        self.file.sync_all().unwrap();
+        let res = if self.force {
+            self.tmp_path.persist(&self.out_path)
+        } else {
+            self.tmp_path.persist_noclobber(&self.out_path)
+        };
+        if let Err(e) = res {
+            panic!("{}: {}", self.out_path.display(), e.error);
+        }
+    }
+}
@@ -1,53 +1,415 @@
-use std::{fs::OpenOptions, sync::Arc};
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::sync::Arc;
 
-use arrow::datatypes::Schema;
-use parquet::{arrow::{arrow_to_parquet_schema, arrow_writer::{get_column_writers, ArrowColumnChunk, ArrowLeafColumn}}, basic::Compression, file::{properties::WriterProperties, writer::SerializedFileWriter}};
+use arrow::datatypes::{DataType, Schema, SchemaRef};
+use arrow::ipc::writer::{DictionaryTracker, IpcDataGenerator, IpcWriteOptions};
+use arrow::row::{Row, RowConverter, Rows, SortField};
+use arrow_array::RecordBatch;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use nom::bytes::complete as nombc;
+use nom::character::complete as nomcc;
+use parquet::{format::SortingColumn, arrow::{ARROW_SCHEMA_META_KEY, arrow_to_parquet_schema, arrow_writer::{compute_leaves, get_column_writers, ArrowColumnChunk}}, basic::{BrotliLevel, Compression, Encoding, GzipLevel, ZstdLevel}, file::{metadata::KeyValue, properties::{EnabledStatistics, WriterProperties, WriterPropertiesPtr, WriterVersion}, writer::SerializedFileWriter}, schema::types::{ColumnPath, SchemaDescriptor}};
 use rayon::prelude::*;
 
-pub fn write_data(out_path: &Path,
-                    to_write:Vec<Vec<ArrowLeafColumn>>,
-                    schema: &Schema){
-    let parquet_schema = arrow_to_parquet_schema(&schema).unwrap();
-    let props = Arc::new(
-        WriterProperties::builder()
-            .set_compression(Compression::SNAPPY)
-            .build(),
-    );
-
-    let of = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(out_path)
-        .unwrap();
-    let root_schema = parquet_schema.root_schema_ptr();
-    let mut writer = SerializedFileWriter::new(of, root_schema, props.clone()).unwrap();
-
-    // Start row group
-    let mut row_group = writer.next_row_group().unwrap();
-    let mut col_writers =
-        get_column_writers(&parquet_schema, &props, &Arc::new(schema.clone())).unwrap();
-   
-    col_writers
-        .par_iter_mut()
-        .enumerate()
-        .for_each(|(i,writer)| {
-            for arrays in &to_write {
-                writer.write(&arrays[i]).unwrap();
+use super::output::BatchSink;
+use super::translate::sorted_by;
+
+const DEFAULT_GZIP_LEVEL:i32 = 6;
+const DEFAULT_ZSTD_LEVEL:i32 = 3;
+const DEFAULT_BROTLI_LEVEL:i32 = 4;
+
+/// Settings of a single column, overriding those of the file
+#[derive(Debug, Clone, Default)]
+pub struct ColumnOptions {
+    pub compression: Option<Compression>,
+    pub dictionary: Option<bool>,
+    /// Encoding of the values, or of those that do not fit in the
+    /// dictionary if dictionary encoding is on
+    pub encoding: Option<Encoding>,
+    pub statistics: Option<EnabledStatistics>,
+    pub bloom_filter: bool,
+    /// False positive probability of the bloom filter
+    pub bloom_filter_fpp: Option<f64>,
+    /// Distinct values in a row group, to size the bloom filter.
+    /// Counted in each row group if not given.
+    pub bloom_filter_ndv: Option<u64>,
+}
+
+/// Settings of the parquet writer
+#[derive(Debug, Clone)]
+pub struct ParquetOptions {
+    pub compression: Compression,
+    pub dictionary: bool,
+    /// Bytes in a data page, roughly
+    pub data_page_size: Option<usize>,
+    /// Rows in a row group
+    pub row_group_size: Option<usize>,
+    pub statistics: Option<EnabledStatistics>,
+    /// Write floats with BYTE_STREAM_SPLIT instead of a dictionary
+    pub byte_stream_split: bool,
+    /// Write integers with DELTA_BINARY_PACKED instead of a dictionary
+    pub delta_integers: bool,
+    pub writer_version: Option<WriterVersion>,
+    /// False positive probability of bloom filters without their own
+    pub bloom_filter_fpp: Option<f64>,
+    /// Settings of columns by name
+    pub columns: HashMap<String, ColumnOptions>,
+}
+
+impl Default for ParquetOptions {
+    fn default() -> Self {
+        ParquetOptions {
+            compression: Compression::SNAPPY,
+            dictionary: true,
+            data_page_size: None,
+            row_group_size: None,
+            statistics: None,
+            byte_stream_split: false,
+            delta_integers: false,
+            writer_version: None,
+            bloom_filter_fpp: None,
+            columns: HashMap::new(),
+        }
+    }
+}
+
+impl ParquetOptions {
+    /// The writer properties for a file with `schema`, with bloom filters
+    /// sized by `ndv` where not given. Columns not in the schema are ignored.
+    fn writer_properties(&self, schema: &Schema, ndv: &HashMap<usize, u64>) -> WriterProperties {
+        let mut builder = WriterProperties::builder()
+            .set_compression(self.compression)
+            .set_dictionary_enabled(self.dictionary)
+            .set_sorting_columns(sorting_columns(schema))
+            .set_key_value_metadata(Some(key_value_metadata(schema)));
+        if let Some(n) = self.data_page_size {
+            builder = builder.set_data_page_size_limit(n);
+        }
+        if let Some(n) = self.row_group_size {
+            builder = builder.set_max_row_group_size(n.max(1));
+        }
+        if let Some(s) = self.statistics {
+            builder = builder.set_statistics_enabled(s);
+        }
+        if let Some(v) = self.writer_version {
+            builder = builder.set_writer_version(v);
+        }
+        for (i, field) in schema.fields().iter().enumerate() {
+            let path = ColumnPath::new(vec![field.name().clone()]);
+            let default = ColumnOptions::default();
+            let col = self.columns.get(field.name()).unwrap_or(&default);
+            let encoding = col.encoding.or(match field.data_type() {
+                DataType::Float32 | DataType::Float64 if self.byte_stream_split => Some(Encoding::BYTE_STREAM_SPLIT),
+                DataType::Int8 | DataType::Int16 | DataType::Int32 | DataType::Int64 if self.delta_integers => {
+                    Some(Encoding::DELTA_BINARY_PACKED)
+                }
+                _ => None,
+            });
+            if let Some(e) = encoding {
+                builder = builder.set_column_encoding(path.clone(), e);
             }
-        });
-    let chunks:Vec<ArrowColumnChunk> = 
-                col_writers.into_par_iter()
-                    .map(|writer|{
-                        return writer.close().unwrap();
-                    })
-                    .collect();
-    chunks.into_iter().for_each(|chunk| {
-              chunk.append_to_row_group(&mut row_group).unwrap();
-        });
-    
-
-    row_group.close().unwrap();
-
-    writer.close().unwrap();
+            //An encoding given for a column replaces its dictionary unless asked for
+            match col.dictionary {
+                Some(d) => builder = builder.set_column_dictionary_enabled(path.clone(), d),
+                None if encoding.is_some() => builder = builder.set_column_dictionary_enabled(path.clone(), false),
+                None => {}
+            }
+            if let Some(c) = col.compression {
+                builder = builder.set_column_compression(path.clone(), c);
+            }
+            if col.bloom_filter {
+                builder = builder.set_column_bloom_filter_enabled(path.clone(), true);
+                if let Some(fpp) = col.bloom_filter_fpp.or(self.bloom_filter_fpp) {
+                    builder = builder.set_column_bloom_filter_fpp(path.clone(), fpp);
+                }
+                if let Some(n) = col.bloom_filter_ndv.or(ndv.get(&i).copied()) {
+                    builder = builder.set_column_bloom_filter_ndv(path.clone(), n.max(1));
+                }
+                //Readers looking up values also use the column index,
+                //which is only written with page statistics
+                if col.statistics.is_none() {
+                    builder = builder.set_column_statistics_enabled(path.clone(), EnabledStatistics::Page);
+                }
+            }
+            if let Some(s) = col.statistics {
+                builder = builder.set_column_statistics_enabled(path, s);
+            }
+        }
+        builder.build()
+    }
+}
+
+/// The schema metadata, readable without decoding the Arrow schema,
+/// followed by the Arrow schema itself
+fn key_value_metadata(schema: &Schema) -> Vec<KeyValue> {
+    let mut kv: Vec<KeyValue> = schema
+        .metadata()
+        .iter()
+        .map(|(k, v)| KeyValue::new(k.clone(), v.clone()))
+        .collect();
+    kv.sort_by(|a, b| a.key.cmp(&b.key));
+    kv.push(KeyValue::new(ARROW_SCHEMA_META_KEY.to_string(), encoded_arrow_schema(schema)));
+    kv
+}
+
+/// The Arrow schema as the parquet arrow reader looks for it under
+/// `ARROW:schema`, keeping the types and field metadata that the parquet
+/// schema lacks
+fn encoded_arrow_schema(schema: &Schema) -> String {
+    let options = IpcWriteOptions::default();
+    let mut tracker = DictionaryTracker::new_with_preserve_dict_id(true, options.preserve_dict_id());
+    let message = IpcDataGenerator::default().schema_to_bytes_with_dictionary_tracker(schema, &mut tracker, &options);
+    //Prefixed by a continuation marker and length, as in the legacy IPC format
+    let mut bytes = vec![255u8; 4];
+    bytes.extend_from_slice(&(message.ipc_message.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&message.ipc_message);
+    BASE64_STANDARD.encode(bytes)
+}
+
+/// Streaming parquet writer
+///
+/// Batches are buffered until they make up a full row group.
+/// The columns of each row group are then encoded in parallel
+/// and appended to the file. Bloom filters without a given number
+/// of distinct values are sized by counting those of the row group.
+pub struct ParquetSink<W: Write + Send> {
+    writer: SerializedFileWriter<W>,
+    schema: SchemaRef,
+    parquet_schema: SchemaDescriptor,
+    opts: ParquetOptions,
+    props: WriterPropertiesPtr,
+    /// Columns whose distinct values are counted
+    count_distinct: Vec<usize>,
+    pending: Vec<RecordBatch>,
+    pending_rows: usize,
 }
+
+impl<W: Write + Send> ParquetSink<W> {
+    pub fn new(out: W, schema: SchemaRef, opts: &ParquetOptions) -> ParquetSink<W> {
+        let parquet_schema = arrow_to_parquet_schema(&schema).unwrap();
+        let props = Arc::new(opts.writer_properties(&schema, &HashMap::new()));
+        let root_schema = parquet_schema.root_schema_ptr();
+        let writer = SerializedFileWriter::new(out, root_schema, props.clone()).unwrap();
+        let count_distinct = schema
+            .fields()
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| {
+                opts.columns
+                    .get(f.name())
+                    .is_some_and(|c| c.bloom_filter && c.bloom_filter_ndv.is_none())
+            })
+            .map(|(i, _)| i)
+            .collect();
+        ParquetSink {
+            writer,
+            schema,
+            parquet_schema,
+            opts: opts.clone(),
+            props,
+            count_distinct,
+            pending: Vec::new(),
+            pending_rows: 0,
+        }
+    }
+
+    fn flush_row_group(&mut self) {
+        if self.pending_rows == 0 {
+            return;
+        }
+        let to_write = std::mem::take(&mut self.pending);
+        self.pending_rows = 0;
+
+        let props = if self.count_distinct.is_empty() {
+            self.props.clone()
+        } else {
+            let ndv = self
+                .count_distinct
+                .par_iter()
+                .map(|&i| (i, distinct_values(&to_write, i)))
+                .collect();
+            Arc::new(self.opts.writer_properties(&self.schema, &ndv))
+        };
+        let mut row_group = self.writer.next_row_group().unwrap();
+        let mut col_writers =
+            get_column_writers(&self.parquet_schema, &props, &self.schema).unwrap();
+        let fields = self.schema.fields();
+
+        col_writers
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(i,writer)| {
+                for batch in &to_write {
+                    for leaf in compute_leaves(&fields[i], batch.column(i)).unwrap() {
+                        writer.write(&leaf).unwrap();
+                    }
+                }
+            });
+        let chunks:Vec<ArrowColumnChunk> =
+                    col_writers.into_par_iter()
+                        .map(|writer| writer.close().unwrap())
+                        .collect();
+        chunks.into_iter().for_each(|chunk| {
+                  chunk.append_to_row_group(&mut row_group).unwrap();
+            });
+
+        row_group.close().unwrap();
+    }
+}
+
+impl<W: Write + Send> BatchSink for ParquetSink<W> {
+    fn write(&mut self, batch: &RecordBatch) {
+        let max_rows = self.props.max_row_group_size();
+        let mut batch = batch.clone();
+        while self.pending_rows + batch.num_rows() >= max_rows {
+            let n = max_rows - self.pending_rows;
+            self.pending.push(batch.slice(0, n));
+            self.pending_rows += n;
+            self.flush_row_group();
+            batch = batch.slice(n, batch.num_rows() - n);
+        }
+        if batch.num_rows() > 0 {
+            self.pending_rows += batch.num_rows();
+            self.pending.push(batch);
+        }
+    }
+
+    fn finish(mut self: Box<Self>) {
+        self.flush_row_group();
+        let mut out = self.writer.into_inner().unwrap();
+        out.flush().unwrap();
+    }
+}
+
+/// Number of distinct values in column `i` of `batches`
+fn distinct_values(batches: &[RecordBatch], i: usize) -> u64 {
+    let Some(first) = batches.first() else { return 0 };
+    let converter = RowConverter::new(vec![SortField::new(first.column(i).data_type().clone())]).unwrap();
+    let rows: Vec<Rows> = batches
+        .iter()
+        .map(|b| converter.convert_columns(&[b.column(i).clone()]).unwrap())
+        .collect();
+    let distinct: HashSet<Row<'_>> = rows.iter().flat_map(|r| r.iter()).collect();
+    distinct.len() as u64
+}
+
+/// The sort order recorded in the schema metadata, as far as its
+/// columns are in the schema. Stata sorts missing values last.
+fn sorting_columns(schema: &Schema) -> Option<Vec<SortingColumn>> {
+    let columns: Vec<SortingColumn> = sorted_by(schema)
+        .into_iter()
+        .map_while(|c| schema.index_of(c).ok())
+        .map(|i| SortingColumn::new(i as i32, false, false))
+        .collect();
+    if columns.is_empty() {
+        None
+    } else {
+        Some(columns)
+    }
+}
+
+/// Parse an encoding other than the dictionary ones, which are
+/// chosen by the dictionary setting
+pub fn parse_encoding(s: &str) -> Result<Encoding, &'static str> {
+    match s.to_ascii_lowercase().parse() {
+        Ok(Encoding::PLAIN_DICTIONARY | Encoding::RLE_DICTIONARY) => Err("Dictionary encoding is set by the dictionary setting"),
+        Ok(e) => Ok(e),
+        Err(_) => Err("Invalid encoding"),
+    }
+}
+
+/// Parse a statistics level: none, chunk or page
+pub fn parse_statistics(s: &str) -> Result<EnabledStatistics, &'static str> {
+    s.to_ascii_lowercase().parse().map_err(|_| "Invalid statistics level")
+}
+
+/// Parse a writer version: 1 or 2
+pub fn parse_writer_version(s: &str) -> Result<WriterVersion, &'static str> {
+    match s.to_ascii_lowercase().trim_start_matches('v') {
+        "1" | "1.0" => Ok(WriterVersion::PARQUET_1_0),
+        "2" | "2.0" => Ok(WriterVersion::PARQUET_2_0),
+        _ => Err("Invalid writer version"),
+    }
+}
+
+/// Parse a compression such as `snappy`, `gzip(9)` or `zstd`,
+/// with the default level if none is given
+pub fn parse_compression(s: &str) -> Result<Compression, &'static str> {
+    match p_compress(s){
+        Ok((_,c)) => Ok(c),
+        Err(_) => Err("Invalid compression parameter")
+    }
+}
+
+fn p_compress(s: &str) -> nom::IResult<&str,Compression> {
+    let (s,c) = nom::branch::alt((
+        p_uncompressed,p_snappy,p_lzo,p_lz4_raw,p_lz4,
+        p_gzip,p_zstd,p_brotli
+    ))(s)?;
+    let (s,_) = nom::combinator::eof(s)?;
+    Ok((s,c))
+}
+ 
+fn p_uncompressed(s: &str) -> nom::IResult<&str,Compression> {
+    let (s,_) = nom::branch::alt((nombc::tag_no_case("uncompressed"),nombc::tag_no_case("none")))(s)?;
+    Ok((s,Compression::UNCOMPRESSED))
+}
+
+fn p_snappy(s: &str) -> nom::IResult<&str,Compression> {
+    let (s,_) = nombc::tag_no_case("snappy")(s)?;
+    Ok((s,Compression::SNAPPY))
+}
+
+fn p_lzo(s: &str) -> nom::IResult<&str,Compression> {
+    let (s,_) = nombc::tag_no_case("lzo")(s)?;
+    Ok((s,Compression::LZO))
+}
+
+fn p_lz4(s: &str) -> nom::IResult<&str,Compression> {
+    let (s,_) = nombc::tag_no_case("lz4")(s)?;
+    Ok((s,Compression::LZ4))
+}
+
+fn p_lz4_raw(s: &str) -> nom::IResult<&str,Compression> {
+    let (s,_) = nombc::tag_no_case("lz4_raw")(s)?;
+    Ok((s,Compression::LZ4_RAW))
+}
+
+fn p_gzip(s: &str) -> nom::IResult<&str,Compression> {
+    let (s,_) = nombc::tag_no_case("gzip")(s)?;
+    let (s,lvl) = p_optno(s,DEFAULT_GZIP_LEVEL)?;
+    let lvl = match GzipLevel::try_new(lvl.try_into().unwrap()) {
+        Ok(l) => l,
+        Err(_) => panic!("Invalid gzip level")
+    };
+    Ok((s,Compression::GZIP(lvl)))
+}
+
+fn p_zstd(s: &str) -> nom::IResult<&str,Compression> {
+    let (s,_) = nombc::tag_no_case("zstd")(s)?;
+    let (s,lvl) = p_optno(s,DEFAULT_ZSTD_LEVEL)?;
+    let lvl = match ZstdLevel::try_new(lvl) {
+        Ok(l) => l,
+        Err(_) => panic!("Invalid zstd level")
+    };
+    Ok((s,Compression::ZSTD(lvl)))
+}
+
+fn p_brotli(s: &str) -> nom::IResult<&str,Compression> {
+    let (s,_) = nombc::tag_no_case("brotli")(s)?;
+    let (s,lvl) = p_optno(s,DEFAULT_BROTLI_LEVEL)?;
+    let lvl = match BrotliLevel::try_new(lvl.try_into().unwrap()) {
+        Ok(l) => l,
+        Err(_) => panic!("Invalid brotli level")
+    };
+    Ok((s,Compression::BROTLI(lvl)))
+}
+
+fn p_optno(s:&str,n:i32) -> nom::IResult<&str,i32> {
+    nom::branch::alt((
+        nom::sequence::delimited(nombc::tag("("),nomcc::i32,nombc::tag(")")),
+        nom::combinator::value(n,nom::combinator::eof)
+    ))(s)
+}
+
@@ -0,0 +1,279 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arrow::array::{Array, ArrayRef, AsArray, UInt32Array};
+use arrow::compute::take;
+use arrow::datatypes::{DataType, Float32Type, Float64Type, Int16Type, Int32Type, Int8Type, Schema, SchemaRef};
+use arrow::row::{Row, RowConverter, SortField};
+use arrow::util::display::array_value_to_string;
+use arrow_array::RecordBatch;
+
+use super::output::{open_sink, BatchSink, OutputOptions, STDOUT};
+use super::stata::dates::{render_date, DateKind, DateStyle};
+use super::stata::{ValueLabelTable, Var};
+use super::translate::{sorted_by, sorted_by_metadata};
+
+/// Directory name used by Hive for null partition values
+pub const HIVE_DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+#[derive(Debug, Clone)]
+pub struct PartitionOptions {
+    /// Columns to partition by, outermost directory first
+    pub columns: Vec<String>,
+    /// Name directories by the value labels of labelled values
+    pub labels: bool,
+    /// Most partition files open at once
+    pub max_open: usize,
+}
+
+struct PartitionColumn {
+    name: String,
+    index: usize,
+    date: Option<DateKind>,
+    labels: Option<Arc<ValueLabelTable>>,
+}
+
+struct OpenPartition {
+    sink: Box<dyn BatchSink>,
+    /// Value of `PartitionedSink::clock` when last written to
+    last_used: u64,
+}
+
+/// Writer of a Hive style directory tree, as in `out/year=2020/state=CA/part-0.parquet`
+///
+/// Rows are sent to a file per combination of values of the partition
+/// columns, which are left out of the files. At most `max_open` files
+/// are kept open; when another is needed the least recently used one is
+/// finished, and writing to its partition later starts a new part file.
+///
+/// A root directory that is not empty is only written to with `force`,
+/// and then the part files of an earlier run that were not written over
+/// are removed when the sink is finished.
+pub struct PartitionedSink {
+    root: PathBuf,
+    opts: OutputOptions,
+    vars: Vec<Var>,
+    /// The schema of the files
+    schema: SchemaRef,
+    /// Indices in the input batches of the columns of `schema`
+    kept: Vec<usize>,
+    partition_columns: Vec<PartitionColumn>,
+    /// Encodes the values of the partition columns of a row, to group rows by
+    converter: RowConverter,
+    open: HashMap<PathBuf, OpenPartition>,
+    /// Number of parts started in each partition directory
+    parts: HashMap<PathBuf, usize>,
+    /// The part files started
+    written: HashSet<PathBuf>,
+    max_open: usize,
+    clock: u64,
+}
+
+impl PartitionedSink {
+    pub fn new(root: &Path, schema: SchemaRef, vars: &[Var], opts: &OutputOptions) -> PartitionedSink {
+        let popts = opts.partition.as_ref().unwrap();
+        if root.as_os_str() == STDOUT {
+            panic!("Partitioned output must go to a directory");
+        }
+        if !opts.force && fs::read_dir(root).is_ok_and(|mut d| d.next().is_some()) {
+            panic!("{} is not empty, use --force to overwrite it", root.display());
+        }
+        let partition_columns: Vec<PartitionColumn> = popts
+            .columns
+            .iter()
+            .map(|name| {
+                let index = schema
+                    .index_of(name)
+                    .unwrap_or_else(|_| panic!("No variable {} to partition by", name));
+                let var = vars.iter().find(|v| &v.name == name);
+                PartitionColumn {
+                    name: name.clone(),
+                    index,
+                    date: var.and_then(|v| DateKind::from_format(&v.format)),
+                    labels: var.and_then(|v| v.dictionary.clone()).filter(|_| popts.labels),
+                }
+            })
+            .collect();
+        let kept: Vec<usize> = (0..schema.fields().len())
+            .filter(|i| !partition_columns.iter().any(|c| c.index == *i))
+            .collect();
+        //The rows of a partition stay sorted without its constant columns
+        let mut metadata = schema.metadata().clone();
+        let sort_columns: Vec<&str> = sorted_by(&schema)
+            .into_iter()
+            .filter(|c| !popts.columns.iter().any(|p| p == c))
+            .collect();
+        metadata.extend(sorted_by_metadata(&sort_columns));
+        let file_schema = Arc::new(Schema::new_with_metadata(
+            kept.iter().map(|&i| schema.field(i).clone()).collect::<Vec<_>>(),
+            metadata,
+        ));
+        let converter = RowConverter::new(
+            partition_columns.iter().map(|c| SortField::new(schema.field(c.index).data_type().clone())).collect(),
+        )
+        .unwrap();
+        let mut opts = opts.clone();
+        opts.partition = None;
+        PartitionedSink {
+            root: root.to_path_buf(),
+            opts,
+            vars: vars.to_vec(),
+            schema: file_schema,
+            kept,
+            partition_columns,
+            converter,
+            open: HashMap::new(),
+            parts: HashMap::new(),
+            written: HashSet::new(),
+            max_open: popts.max_open.max(1),
+            clock: 0,
+        }
+    }
+
+    /// The partition directory of `row`, relative to the root
+    fn partition_dir(&self, batch: &RecordBatch, row: usize) -> PathBuf {
+        let mut dir = PathBuf::new();
+        for c in &self.partition_columns {
+            let value = partition_value(batch.column(c.index), row, c);
+            dir.push(format!("{}={}", escape_path_name(&c.name), escape_path_name(&value)));
+        }
+        dir
+    }
+
+    fn sink(&mut self, dir: &Path) -> &mut Box<dyn BatchSink> {
+        self.clock += 1;
+        if !self.open.contains_key(dir) {
+            if self.open.len() >= self.max_open {
+                let lru = self
+                    .open
+                    .iter()
+                    .min_by_key(|(_, p)| p.last_used)
+                    .map(|(d, _)| d.clone())
+                    .unwrap();
+                self.open.remove(&lru).unwrap().sink.finish();
+            }
+            let part = self.parts.entry(dir.to_path_buf()).or_insert(0);
+            let full_dir = self.root.join(dir);
+            fs::create_dir_all(&full_dir).unwrap_or_else(|e| panic!("{}: {}", full_dir.display(), e));
+            let path = full_dir.join(format!("part-{}.{}", part, self.opts.format.extension()));
+            *part += 1;
+            let sink = open_sink(&path, self.schema.clone(), &self.vars, &self.opts);
+            self.written.insert(path);
+            self.open.insert(dir.to_path_buf(), OpenPartition { sink, last_used: 0 });
+        }
+        let p = self.open.get_mut(dir).unwrap();
+        p.last_used = self.clock;
+        &mut p.sink
+    }
+}
+
+impl BatchSink for PartitionedSink {
+    fn write(&mut self, batch: &RecordBatch) {
+        //Rows of each combination of partition values, in order of first
+        //appearance, with the directory named once per combination
+        let columns: Vec<ArrayRef> = self.partition_columns.iter().map(|c| batch.column(c.index).clone()).collect();
+        let keys = self.converter.convert_columns(&columns).unwrap();
+        let mut groups: Vec<Vec<u32>> = Vec::new();
+        let mut group_of: HashMap<Row, usize> = HashMap::new();
+        for (row, key) in keys.iter().enumerate() {
+            let g = *group_of.entry(key).or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+            groups[g].push(row as u32);
+        }
+        //Values such as 0 and -0 can name the same directory
+        let mut dirs: Vec<(PathBuf, Vec<u32>)> = Vec::new();
+        let mut dir_of: HashMap<PathBuf, usize> = HashMap::new();
+        for rows in groups {
+            let dir = self.partition_dir(batch, rows[0] as usize);
+            match dir_of.get(&dir) {
+                Some(&d) => {
+                    dirs[d].1.extend(rows);
+                    dirs[d].1.sort_unstable();
+                }
+                None => {
+                    dir_of.insert(dir.clone(), dirs.len());
+                    dirs.push((dir, rows));
+                }
+            }
+        }
+        for (dir, rows) in dirs {
+            let indices = UInt32Array::from(rows);
+            let columns: Vec<ArrayRef> = self
+                .kept
+                .iter()
+                .map(|&i| take(batch.column(i), &indices, None).unwrap())
+                .collect();
+            let part = RecordBatch::try_new(self.schema.clone(), columns).unwrap();
+            self.sink(&dir).write(&part);
+        }
+    }
+
+    fn finish(mut self: Box<Self>) {
+        for (_, p) in self.open.drain() {
+            p.sink.finish();
+        }
+        //Parts left by an earlier run, and the directories they leave empty
+        let root = glob::Pattern::escape(&self.root.to_string_lossy());
+        let pattern = format!("{}/**/part-*.{}", root, self.opts.format.extension());
+        for path in glob::glob(&pattern).unwrap().map(|p| p.unwrap()) {
+            if self.written.contains(&path) {
+                continue;
+            }
+            fs::remove_file(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+            let mut dir = path.parent();
+            while let Some(d) = dir.filter(|d| *d != self.root) {
+                if fs::remove_dir(d).is_err() {
+                    break;
+                }
+                dir = d.parent();
+            }
+        }
+    }
+}
+
+fn partition_value(a: &ArrayRef, row: usize, c: &PartitionColumn) -> String {
+    if a.is_null(row) {
+        return HIVE_DEFAULT_PARTITION.to_string();
+    }
+    let x = match a.data_type() {
+        DataType::Int8 => a.as_primitive::<Int8Type>().value(row) as f64,
+        DataType::Int16 => a.as_primitive::<Int16Type>().value(row) as f64,
+        DataType::Int32 => a.as_primitive::<Int32Type>().value(row) as f64,
+        DataType::Float32 => a.as_primitive::<Float32Type>().value(row) as f64,
+        DataType::Float64 => a.as_primitive::<Float64Type>().value(row),
+        DataType::Binary => {
+            let s = a.as_binary::<i32>().value(row);
+            return String::from_utf8_lossy(s.strip_suffix(b"\0").unwrap_or(s)).into_owned();
+        }
+        _ => return array_value_to_string(a, row).unwrap(),
+    };
+    if x.fract() == 0.0 {
+        if let Some(l) = c.labels.as_ref().and_then(|t| t.label(x as i32)) {
+            return l.to_string();
+        }
+    }
+    if let Some(kind) = c.date {
+        return render_date(kind, x, DateStyle::Iso);
+    }
+    array_value_to_string(a, row).unwrap()
+}
+
+/// Escape the characters Hive escapes in partition directory names
+fn escape_path_name(s: &str) -> String {
+    if s.is_empty() {
+        return HIVE_DEFAULT_PARTITION.to_string();
+    }
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\u{1}'..='\u{1f}' | '"' | '#' | '%' | '\'' | '*' | '/' | ':' | '=' | '?' | '\\' | '\u{7f}' | '{'
+            | '[' | ']' | '^' => out.push_str(&format!("%{:02X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,253 @@
+use std::collections::{HashMap, HashSet};
+
+use regex::Regex;
+
+use super::stata::Var;
+
+/// Longest name made from a variable label, unless set otherwise
+pub const DEFAULT_MAX_LENGTH: usize = 64;
+
+/// How output column names are made from variable names
+///
+/// A name in `map` is used as it is. Other names start as the variable
+/// name, or with `from_labels` as a slug of the variable label, have the
+/// `replace` substitutions made in order, are then lowercased or put in
+/// snake case, and finally get `prefix` and `suffix`.
+#[derive(Debug, Clone)]
+pub struct NameTransform {
+    /// Names from the variable labels, for the variables with one.
+    /// Names made from labels are told apart by `_2`, `_3` and so on,
+    /// put before `prefix` and `suffix` and kept within `max_length`.
+    pub from_labels: bool,
+    /// Longest slug of a label
+    pub max_length: usize,
+    pub lowercase: bool,
+    /// Lowercase, with words split at case changes and joined by `_`
+    pub snake_case: bool,
+    /// Regular expressions and their replacements, where `$1` and
+    /// so on stand for the groups matched
+    pub replace: Vec<(Regex, String)>,
+    pub prefix: String,
+    pub suffix: String,
+    /// Output names of single variables
+    pub map: HashMap<String, String>,
+}
+
+impl Default for NameTransform {
+    fn default() -> NameTransform {
+        NameTransform {
+            from_labels: false,
+            max_length: DEFAULT_MAX_LENGTH,
+            lowercase: false,
+            snake_case: false,
+            replace: Vec::new(),
+            prefix: String::new(),
+            suffix: String::new(),
+            map: HashMap::new(),
+        }
+    }
+}
+
+impl NameTransform {
+    /// The output names of `vars`
+    pub fn names(&self, vars: &[Var]) -> Vec<String> {
+        let labelled: Vec<bool> = vars
+            .iter()
+            .map(|v| self.from_labels && !self.map.contains_key(&v.name) && !slug(&v.var_label, self.max_length).is_empty())
+            .collect();
+        let mut names: Vec<String> = vars.iter().zip(&labelled).map(|(v, &l)| self.name(v, l)).collect();
+        //Names not made from labels are kept, and clashes left to the caller
+        let mut used: HashSet<String> = names
+            .iter()
+            .zip(&labelled)
+            .filter(|(_, &l)| !l)
+            .map(|(n, _)| n.clone())
+            .collect();
+        //The number goes before the prefix and suffix, and within max_length
+        for ((n, v), _) in names.iter_mut().zip(vars).zip(&labelled).filter(|(_, &l)| l) {
+            let base = self.base_name(v, true);
+            let mut k = 2;
+            while used.contains(n.as_str()) {
+                let tag = format!("_{}", k);
+                let keep = self.max_length.saturating_sub(tag.len()).max(1);
+                let stem: String = base.chars().take(keep).collect();
+                *n = self.affixed(&format!("{}{}", stem.trim_end_matches('_'), tag));
+                k += 1;
+            }
+            used.insert(n.clone());
+        }
+        names
+    }
+
+    fn name(&self, v: &Var, from_label: bool) -> String {
+        match self.map.get(&v.name) {
+            Some(n) => n.clone(),
+            None => self.affixed(&self.base_name(v, from_label)),
+        }
+    }
+
+    /// The name before `prefix` and `suffix` are added
+    fn base_name(&self, v: &Var, from_label: bool) -> String {
+        let mut n = if from_label { slug(&v.var_label, self.max_length) } else { v.name.clone() };
+        for (re, with) in &self.replace {
+            n = re.replace_all(&n, with.as_str()).into_owned();
+        }
+        if self.snake_case {
+            n = snake_case(&n);
+        } else if self.lowercase {
+            n = n.to_lowercase();
+        }
+        n
+    }
+
+    fn affixed(&self, n: &str) -> String {
+        format!("{}{}{}", self.prefix, n, self.suffix)
+    }
+}
+
+/// Parse a regex substitution given as `PATTERN=REPLACEMENT`
+pub fn parse_replacement(s: &str) -> Result<(Regex, String), String> {
+    let (pattern, with) = s.split_once('=').ok_or("Expected PATTERN=REPLACEMENT")?;
+    let re = Regex::new(pattern).map_err(|e| e.to_string())?;
+    Ok((re, with.to_string()))
+}
+
+/// A lowercase name from a label, with runs of characters other than
+/// letters and digits replaced by `_`, cut to `max_length` characters,
+/// and `_` put first if it would start with a digit
+fn slug(label: &str, max_length: usize) -> String {
+    let mut out = String::new();
+    for c in label.chars() {
+        if c.is_alphanumeric() {
+            out.extend(c.to_lowercase());
+        } else if !out.is_empty() && !out.ends_with('_') {
+            out.push('_');
+        }
+    }
+    if out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    let mut out: String = out.chars().take(max_length.max(1)).collect();
+    while out.ends_with('_') {
+        out.pop();
+    }
+    out
+}
+
+/// `HouseholdID` to `household_id`, `q17b` to `q17b`
+///
+/// Words start at a capital after a lowercase letter, or at the last
+/// capital of a run followed by a lowercase letter, as in `HHSize`.
+/// Characters other than letters and digits become `_`, and repeated
+/// and trailing `_` are dropped.
+fn snake_case(s: &str) -> String {
+    let chars: Vec<char> = s.chars().collect();
+    let mut out = String::with_capacity(s.len() + 4);
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !out.ends_with('_') {
+                out.push('_');
+            }
+            continue;
+        }
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if (prev.is_lowercase() || (prev.is_uppercase() && next_lower)) && !out.ends_with('_') {
+                out.push('_');
+            }
+        }
+        out.extend(c.to_lowercase());
+    }
+    while out.len() > 1 && out.ends_with('_') {
+        out.pop();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stata::VarType;
+
+    fn var(name: &str, var_label: &str) -> Var {
+        Var {
+            ty: VarType::TDouble,
+            name: name.to_string(),
+            format: String::new(),
+            value_label: String::new(),
+            var_label: var_label.to_string(),
+            dictionary: None,
+        }
+    }
+
+    #[test]
+    fn snake_case_words() {
+        assert_eq!(snake_case("HouseholdID"), "household_id");
+        assert_eq!(snake_case("HHSize"), "hh_size");
+        assert_eq!(snake_case("householdSize"), "household_size");
+        assert_eq!(snake_case("q17b"), "q17b");
+        assert_eq!(snake_case("Q17b_3"), "q17b_3");
+        assert_eq!(snake_case("income__2020_"), "income_2020");
+        assert_eq!(snake_case("_"), "_");
+    }
+
+    #[test]
+    fn slug_labels() {
+        assert_eq!(slug("Household income (USD)", 64), "household_income_usd");
+        assert_eq!(slug("  Size of household? ", 64), "size_of_household");
+        assert_eq!(slug("2nd visit", 64), "_2nd_visit");
+        assert_eq!(slug("Size of household", 8), "size_of");
+        assert_eq!(slug("9", 1), "");
+        assert_eq!(slug("--", 64), "");
+    }
+
+    #[test]
+    fn names_from_labels() {
+        let t = NameTransform { from_labels: true, ..Default::default() };
+        let vars = [var("hhid", "Household ID"), var("v2", ""), var("v3", "1st wave"), var("v4", "?")];
+        assert_eq!(t.names(&vars), ["household_id", "v2", "_1st_wave", "v4"]);
+    }
+
+    #[test]
+    fn names_numbered_within_max_length() {
+        let t = NameTransform { from_labels: true, max_length: 10, ..Default::default() };
+        let vars: Vec<Var> = (0..10).map(|i| var(&format!("v{}", i), "Age of head")).collect();
+        assert_eq!(
+            t.names(&vars),
+            [
+                "age_of_hea",
+                "age_of_h_2",
+                "age_of_h_3",
+                "age_of_h_4",
+                "age_of_h_5",
+                "age_of_h_6",
+                "age_of_h_7",
+                "age_of_h_8",
+                "age_of_h_9",
+                "age_of_10"
+            ]
+        );
+    }
+
+    #[test]
+    fn names_numbered_before_affixes() {
+        let t = NameTransform {
+            from_labels: true,
+            prefix: "x_".to_string(),
+            suffix: "_y".to_string(),
+            ..Default::default()
+        };
+        //A name not made from a label is kept, and a label clashing with it is numbered
+        let vars = [var("a", "Age"), var("age", ""), var("b", "AGE")];
+        assert_eq!(t.names(&vars), ["x_age_2_y", "x_age_y", "x_age_3_y"]);
+    }
+
+    #[test]
+    fn names_mapped_and_snake_case() {
+        let mut t = NameTransform { snake_case: true, ..Default::default() };
+        t.map.insert("HHSize".to_string(), "size".to_string());
+        let vars = [var("HouseholdID", "Household"), var("HHSize", "Size")];
+        assert_eq!(t.names(&vars), ["household_id", "size"]);
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashSet;
+use std::ops::Range;
+
+use arrow::array::BooleanArray;
+
+use super::filter::Filter;
+
+/// A random sample of rows
+#[derive(Debug, Clone, Copy)]
+pub enum Sample {
+    /// Each row is kept with this probability
+    Fraction(f64),
+    /// Exactly this many rows, or all if there are fewer
+    Count(usize),
+}
+
+/// Which rows of a file to convert
+///
+/// Rows are first restricted to `start..end`, then sampled, and then
+/// filtered by `filter`. Samples are drawn from `seed`, so the same seed
+/// gives the same rows.
+#[derive(Debug, Clone, Default)]
+pub struct RowSelection {
+    pub start: usize,
+    /// End of the range, or the end of the file if `None`
+    pub end: Option<usize>,
+    pub sample: Option<Sample>,
+    pub seed: u64,
+    pub filter: Option<Filter>,
+}
+
+enum Picked {
+    All,
+    /// Rows whose hash is below the threshold
+    Hashed(u64),
+    /// Sorted row numbers
+    List(Vec<usize>),
+}
+
+/// The rows picked by a `RowSelection` from a file, before filtering
+pub struct SelectedRows {
+    range: Range<usize>,
+    seed: u64,
+    picked: Picked,
+}
+
+impl RowSelection {
+    /// Pick the rows of a file with `nobs` rows
+    pub fn pick(&self, nobs: usize) -> SelectedRows {
+        let end = self.end.map_or(nobs, |e| e.min(nobs));
+        let range = self.start.min(end)..end;
+        let picked = match self.sample {
+            None => Picked::All,
+            Some(Sample::Fraction(f)) if f >= 1.0 => Picked::All,
+            Some(Sample::Fraction(f)) => Picked::Hashed((f.max(0.0) * u64::MAX as f64) as u64),
+            Some(Sample::Count(n)) if n >= range.len() => Picked::All,
+            Some(Sample::Count(n)) => Picked::List(floyd_sample(range.clone(), n, self.seed)),
+        };
+        SelectedRows { range, seed: self.seed, picked }
+    }
+}
+
+impl SelectedRows {
+    /// The rows to read
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    /// Which of the rows `start..end` are picked, or `None` if all are
+    pub fn mask(&self, start: usize, end: usize) -> Option<BooleanArray> {
+        match &self.picked {
+            Picked::All => None,
+            Picked::Hashed(threshold) => Some(
+                (start..end)
+                    .map(|r| Some(row_hash(self.seed, r) < *threshold))
+                    .collect(),
+            ),
+            Picked::List(rows) => {
+                let i = rows.partition_point(|&r| r < start);
+                let j = rows.partition_point(|&r| r < end);
+                let mut keep = vec![false; end - start];
+                for &r in &rows[i..j] {
+                    keep[r - start] = true;
+                }
+                Some(BooleanArray::from(keep))
+            }
+        }
+    }
+
+    /// Whether any of the rows `start..end` is picked
+    pub fn any(&self, start: usize, end: usize) -> bool {
+        match &self.picked {
+            Picked::All => start < end,
+            Picked::Hashed(threshold) => (start..end).any(|r| row_hash(self.seed, r) < *threshold),
+            Picked::List(rows) => rows.partition_point(|&r| r < start) < rows.partition_point(|&r| r < end),
+        }
+    }
+}
+
+/// The hash deciding whether row `r` is in a sample drawn from `seed`.
+/// The seed is hashed first, as seeds differing in low bits would
+/// otherwise pick the same rows with neighbours swapped.
+fn row_hash(seed: u64, r: usize) -> u64 {
+    splitmix64(splitmix64(seed) ^ r as u64)
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// `n` distinct rows of `range`, sorted, by Floyd's algorithm
+fn floyd_sample(range: Range<usize>, n: usize, seed: u64) -> Vec<usize> {
+    let len = range.len();
+    let mut state = seed;
+    let mut chosen = HashSet::with_capacity(n);
+    for j in (len - n)..len {
+        state = splitmix64(state);
+        let t = (state % (j as u64 + 1)) as usize;
+        if !chosen.insert(t) {
+            chosen.insert(j);
+        }
+    }
+    let mut rows: Vec<usize> = chosen.into_iter().map(|r| r + range.start).collect();
+    rows.sort_unstable();
+    rows
+}
@@ -0,0 +1,437 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use arrow::array::{Array, ArrayRef, UInt32Array};
+use arrow::compute::{self, concat_batches, interleave, take};
+use arrow::datatypes::SchemaRef;
+use arrow::ipc::reader::StreamReader;
+use arrow::ipc::writer::{IpcWriteOptions, StreamWriter};
+use arrow::ipc::CompressionType;
+use arrow::row::{OwnedRow, RowConverter, Rows, SortField};
+use arrow_array::RecordBatch;
+use rayon::prelude::*;
+
+use super::output::BatchSink;
+use super::translate::{sorted_by, sorted_by_metadata};
+
+/// The sort order of Stata, which puts missing values last
+pub const STATA_SORT: compute::SortOptions = compute::SortOptions { descending: false, nulls_first: false };
+
+/// Checks that the rows written through it are sorted as the schema
+/// metadata claims, and warns when finished if they are not
+pub struct SortCheckSink {
+    inner: Box<dyn BatchSink>,
+    /// Name of the data in the warning
+    name: String,
+    /// Names and indices of the sort columns
+    columns: Vec<String>,
+    keys: Vec<usize>,
+    converter: RowConverter,
+    /// Sort key of the last row written
+    last: Option<OwnedRow>,
+    rows: usize,
+    /// Number of the first row that is out of order
+    unsorted_at: Option<usize>,
+}
+
+impl SortCheckSink {
+    pub fn new(inner: Box<dyn BatchSink>, schema: SchemaRef, name: String) -> SortCheckSink {
+        let columns: Vec<String> = sorted_by(&schema)
+            .into_iter()
+            .take_while(|c| schema.index_of(c).is_ok())
+            .map(String::from)
+            .collect();
+        let keys: Vec<usize> = columns.iter().map(|c| schema.index_of(c).unwrap()).collect();
+        let converter = RowConverter::new(
+            keys.iter()
+                .map(|&i| SortField::new_with_options(schema.field(i).data_type().clone(), STATA_SORT))
+                .collect(),
+        )
+        .unwrap();
+        SortCheckSink {
+            inner,
+            name,
+            columns,
+            keys,
+            converter,
+            last: None,
+            rows: 0,
+            unsorted_at: None,
+        }
+    }
+}
+
+impl BatchSink for SortCheckSink {
+    fn write(&mut self, batch: &RecordBatch) {
+        if !self.keys.is_empty() && self.unsorted_at.is_none() && batch.num_rows() > 0 {
+            let columns: Vec<_> = self.keys.iter().map(|&i| batch.column(i).clone()).collect();
+            let rows = self.converter.convert_columns(&columns).unwrap();
+            if self.last.as_ref().is_some_and(|last| last.row() > rows.row(0)) {
+                self.unsorted_at = Some(self.rows);
+            } else if let Some(i) = (1..rows.num_rows()).find(|&i| rows.row(i - 1) > rows.row(i)) {
+                self.unsorted_at = Some(self.rows + i);
+            }
+            self.last = Some(rows.row(rows.num_rows() - 1).owned());
+        }
+        self.rows += batch.num_rows();
+        self.inner.write(batch);
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.inner.bytes_written()
+    }
+
+    fn finish(self: Box<Self>) {
+        if let Some(row) = self.unsorted_at {
+            eprintln!(
+                "Warning: {} claims to be sorted by {} but row {} is out of order",
+                self.name,
+                self.columns.join(", "),
+                row
+            );
+        }
+        self.inner.finish();
+    }
+}
+
+/// Rows in each batch of a spilled run and of the merged output
+const SORT_BATCH_ROWS: usize = 65536;
+
+#[derive(Debug, Clone)]
+pub struct SortOptions {
+    /// Columns to sort by, most significant first
+    pub columns: Vec<String>,
+    /// Bytes of batches held in memory before a sorted run is spilled
+    pub memory: usize,
+    /// Directory for the spilled runs
+    pub temp_dir: Option<PathBuf>,
+}
+
+/// A sorted run spilled to a temporary Arrow IPC stream
+struct Run {
+    file: File,
+}
+
+/// Position of the merge in a run
+struct RunCursor {
+    reader: StreamReader<BufReader<File>>,
+    batch: RecordBatch,
+    rows: Rows,
+    pos: usize,
+    /// Index of `batch` in the merge's batches
+    slot: usize,
+}
+
+/// Sorts the rows written through it by some columns, using
+/// temporary files when they do not fit in memory
+///
+/// Batches are buffered until they take up `memory` bytes, and are then
+/// sorted and spilled as a run. When finished, the runs are merged and
+/// written to the inner sink. Rows with equal keys keep their order.
+pub struct SortingSink {
+    inner: Box<dyn BatchSink>,
+    schema: SchemaRef,
+    opts: SortOptions,
+    keys: Vec<usize>,
+    converter: RowConverter,
+    pending: Vec<RecordBatch>,
+    pending_bytes: usize,
+    runs: Vec<Run>,
+}
+
+impl SortingSink {
+    /// `make_inner` opens the inner sink with the schema of the sorted output,
+    /// which records the sort order
+    pub fn new<F>(schema: SchemaRef, opts: &SortOptions, make_inner: F) -> SortingSink
+    where
+        F: FnOnce(SchemaRef) -> Box<dyn BatchSink>,
+    {
+        let keys: Vec<usize> = opts
+            .columns
+            .iter()
+            .map(|c| schema.index_of(c).unwrap_or_else(|_| panic!("No variable {} to sort by", c)))
+            .collect();
+        let converter = RowConverter::new(
+            keys.iter()
+                .map(|&i| SortField::new_with_options(schema.field(i).data_type().clone(), STATA_SORT))
+                .collect(),
+        )
+        .unwrap();
+        let columns: Vec<&str> = opts.columns.iter().map(String::as_str).collect();
+        let mut metadata = schema.metadata().clone();
+        metadata.extend(sorted_by_metadata(&columns));
+        let schema = Arc::new(schema.as_ref().clone().with_metadata(metadata));
+        SortingSink {
+            inner: make_inner(schema.clone()),
+            schema,
+            opts: opts.clone(),
+            keys,
+            converter,
+            pending: Vec::new(),
+            pending_bytes: 0,
+            runs: Vec::new(),
+        }
+    }
+
+    fn convert(&self, batch: &RecordBatch) -> Rows {
+        let columns: Vec<ArrayRef> = self.keys.iter().map(|&i| batch.column(i).clone()).collect();
+        self.converter.convert_columns(&columns).unwrap()
+    }
+
+    /// Sort the pending batches into one batch
+    fn sort_pending(&mut self) -> RecordBatch {
+        let batch = concat_batches(&self.schema, &self.pending).unwrap();
+        self.pending.clear();
+        self.pending_bytes = 0;
+        let rows = self.convert(&batch);
+        let mut order: Vec<u32> = (0..batch.num_rows() as u32).collect();
+        order.sort_by(|&a, &b| rows.row(a as usize).cmp(&rows.row(b as usize)));
+        let indices = UInt32Array::from(order);
+        let columns: Vec<ArrayRef> = batch
+            .columns()
+            .par_iter()
+            .map(|c| take(c, &indices, None).unwrap())
+            .collect();
+        RecordBatch::try_new(self.schema.clone(), columns).unwrap()
+    }
+
+    fn spill(&mut self) {
+        let sorted = self.sort_pending();
+        let file = match &self.opts.temp_dir {
+            Some(d) => tempfile::tempfile_in(d),
+            None => tempfile::tempfile(),
+        }
+        .unwrap_or_else(|e| panic!("Cannot create a temporary file for sorting: {}", e));
+        let options = IpcWriteOptions::default()
+            .try_with_compression(Some(CompressionType::LZ4_FRAME))
+            .unwrap();
+        let mut writer =
+            StreamWriter::try_new_with_options(BufWriter::new(file.try_clone().unwrap()), &self.schema, options)
+                .unwrap();
+        for start in (0..sorted.num_rows()).step_by(SORT_BATCH_ROWS) {
+            let n = SORT_BATCH_ROWS.min(sorted.num_rows() - start);
+            writer.write(&sorted.slice(start, n)).unwrap();
+        }
+        writer.finish().unwrap();
+        writer.into_inner().unwrap().flush().unwrap();
+        self.runs.push(Run { file });
+    }
+
+    /// Merge the spilled runs into the inner sink
+    fn merge(&mut self) {
+        let mut batches: Vec<RecordBatch> = Vec::new();
+        let mut cursors: Vec<RunCursor> = Vec::new();
+        for run in &self.runs {
+            let mut file = run.file.try_clone().unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            let mut reader = StreamReader::try_new_buffered(file, None).unwrap();
+            if let Some(batch) = reader.next() {
+                let batch = batch.unwrap();
+                let rows = self.convert(&batch);
+                batches.push(batch.clone());
+                cursors.push(RunCursor { reader, batch, rows, pos: 0, slot: batches.len() - 1 });
+            }
+        }
+        //Ties go to the earlier run, which keeps equal rows in order
+        let mut heap: BinaryHeap<Reverse<(OwnedRow, usize)>> = cursors
+            .iter()
+            .enumerate()
+            .map(|(i, c)| Reverse((c.rows.row(0).owned(), i)))
+            .collect();
+        let mut picked: Vec<(usize, usize)> = Vec::with_capacity(SORT_BATCH_ROWS);
+        while let Some(Reverse((_, i))) = heap.pop() {
+            let c = &mut cursors[i];
+            picked.push((c.slot, c.pos));
+            c.pos += 1;
+            if c.pos == c.batch.num_rows() {
+                match c.reader.next() {
+                    Some(batch) => {
+                        let batch = batch.unwrap();
+                        c.rows = self.convert(&batch);
+                        batches.push(batch.clone());
+                        c.batch = batch;
+                        c.pos = 0;
+                        c.slot = batches.len() - 1;
+                    }
+                    None => c.pos = usize::MAX,
+                }
+            }
+            if c.pos != usize::MAX {
+                heap.push(Reverse((c.rows.row(c.pos).owned(), i)));
+            }
+            if picked.len() == SORT_BATCH_ROWS || heap.is_empty() {
+                self.inner.write(&interleave_batches(&self.schema, &batches, &picked));
+                picked.clear();
+                //Keep only the batches the runs are still in
+                batches.clear();
+                for c in &mut cursors {
+                    batches.push(c.batch.clone());
+                    c.slot = batches.len() - 1;
+                }
+            }
+        }
+    }
+}
+
+impl BatchSink for SortingSink {
+    fn write(&mut self, batch: &RecordBatch) {
+        if batch.num_rows() == 0 {
+            return;
+        }
+        self.pending_bytes += batch.get_array_memory_size();
+        self.pending.push(batch.clone());
+        if self.pending_bytes >= self.opts.memory {
+            self.spill();
+        }
+    }
+
+    fn finish(mut self: Box<Self>) {
+        if self.runs.is_empty() {
+            if !self.pending.is_empty() {
+                let sorted = self.sort_pending();
+                for start in (0..sorted.num_rows()).step_by(SORT_BATCH_ROWS) {
+                    let n = SORT_BATCH_ROWS.min(sorted.num_rows() - start);
+                    self.inner.write(&sorted.slice(start, n));
+                }
+            }
+        } else {
+            if !self.pending.is_empty() {
+                self.spill();
+            }
+            self.merge();
+        }
+        self.inner.finish();
+    }
+}
+
+/// The rows `(batch, row)` of `batches`, in order
+fn interleave_batches(schema: &SchemaRef, batches: &[RecordBatch], indices: &[(usize, usize)]) -> RecordBatch {
+    let columns: Vec<ArrayRef> = (0..schema.fields().len())
+        .into_par_iter()
+        .map(|i| {
+            let arrays: Vec<&dyn Array> = batches.iter().map(|b| b.column(i).as_ref()).collect();
+            interleave(&arrays, indices).unwrap()
+        })
+        .collect();
+    RecordBatch::try_new(schema.clone(), columns).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use arrow::array::{AsArray, Int32Array};
+    use arrow::datatypes::{DataType, Field, Int32Type, Schema};
+
+    /// Keeps the batches written to it
+    struct Collect(Arc<Mutex<Vec<RecordBatch>>>);
+
+    impl BatchSink for Collect {
+        fn write(&mut self, batch: &RecordBatch) {
+            self.0.lock().unwrap().push(batch.clone());
+        }
+
+        fn finish(self: Box<Self>) {}
+    }
+
+    /// Sort rows with keys `keys` and ids their positions, written in
+    /// batches of `batch_rows` rows, spilling runs of `memory` bytes.
+    /// Gives the batches written out and the number of runs.
+    fn sort(keys: &[Option<i32>], batch_rows: usize, memory: usize) -> (Vec<RecordBatch>, usize) {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int32, true),
+            Field::new("id", DataType::Int32, false),
+        ]));
+        let opts = SortOptions { columns: vec!["k".to_string()], memory, temp_dir: None };
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let mut sink = SortingSink::new(schema.clone(), &opts, |_| Box::new(Collect(out.clone())));
+        for start in (0..keys.len()).step_by(batch_rows) {
+            let end = keys.len().min(start + batch_rows);
+            let batch = RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(Int32Array::from(keys[start..end].to_vec())),
+                    Arc::new(Int32Array::from_iter_values(start as i32..end as i32)),
+                ],
+            )
+            .unwrap();
+            sink.write(&batch);
+        }
+        let runs = sink.runs.len();
+        Box::new(sink).finish();
+        let batches = out.lock().unwrap().clone();
+        (batches, runs)
+    }
+
+    /// The keys and ids of the rows of `batches`
+    fn pairs(batches: &[RecordBatch]) -> Vec<(Option<i32>, i32)> {
+        batches
+            .iter()
+            .flat_map(|b| {
+                let k = b.column(0).as_primitive::<Int32Type>();
+                let id = b.column(1).as_primitive::<Int32Type>();
+                k.iter().zip(id.values().iter().copied()).collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// The rows sorted by key, missing keys last, keeping the order of equal keys
+    fn stable_sorted(keys: &[Option<i32>]) -> Vec<(Option<i32>, i32)> {
+        let mut rows: Vec<(Option<i32>, i32)> = keys.iter().copied().zip(0..).collect();
+        rows.sort_by_key(|&(k, _)| (k.is_none(), k));
+        rows
+    }
+
+    #[test]
+    fn in_memory() {
+        let keys: Vec<Option<i32>> = (0..1000).map(|i| Some((i * 37) % 11)).collect();
+        let (batches, runs) = sort(&keys, 100, usize::MAX);
+        assert_eq!(runs, 0);
+        assert_eq!(pairs(&batches), stable_sorted(&keys));
+    }
+
+    #[test]
+    fn equal_keys_keep_order_across_runs() {
+        let keys: Vec<Option<i32>> = (0..1000).map(|i| Some((i * 37) % 7)).collect();
+        let (batches, runs) = sort(&keys, 50, 1);
+        assert_eq!(runs, 20);
+        assert_eq!(pairs(&batches), stable_sorted(&keys));
+    }
+
+    #[test]
+    fn nulls_last() {
+        let keys: Vec<Option<i32>> = (0..1000).map(|i| if i % 3 == 0 { None } else { Some(-i % 5) }).collect();
+        let (batches, runs) = sort(&keys, 64, 1);
+        assert!(runs > 1);
+        let rows = pairs(&batches);
+        assert_eq!(rows, stable_sorted(&keys));
+        assert!(rows[..666].iter().all(|(k, _)| k.is_some()));
+        assert!(rows[666..].iter().all(|(k, _)| k.is_none()));
+    }
+
+    #[test]
+    fn run_ends_at_output_batch() {
+        //The first run is used up by exactly the first output batch
+        let keys: Vec<Option<i32>> = (0..2 * SORT_BATCH_ROWS).map(|i| Some((i / SORT_BATCH_ROWS) as i32)).collect();
+        let (batches, runs) = sort(&keys, SORT_BATCH_ROWS, 1);
+        assert_eq!(runs, 2);
+        let sizes: Vec<usize> = batches.iter().map(|b| b.num_rows()).collect();
+        assert_eq!(sizes, [SORT_BATCH_ROWS, SORT_BATCH_ROWS]);
+        assert_eq!(pairs(&batches), stable_sorted(&keys));
+    }
+
+    #[test]
+    fn runs_end_at_output_batches_interleaved() {
+        //Interleaved runs of one spilled batch each, merged into whole output batches
+        let keys: Vec<Option<i32>> = (0..2 * SORT_BATCH_ROWS).map(|i| Some((i % 3) as i32)).collect();
+        let (batches, runs) = sort(&keys, SORT_BATCH_ROWS, 1);
+        assert_eq!(runs, 2);
+        assert!(batches.iter().all(|b| b.num_rows() == SORT_BATCH_ROWS));
+        assert_eq!(pairs(&batches), stable_sorted(&keys));
+    }
+}
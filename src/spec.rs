@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use super::parquet::{parse_compression, parse_encoding, parse_statistics, parse_writer_version, ColumnOptions, ParquetOptions};
+use super::rename::{NameTransform, DEFAULT_MAX_LENGTH};
+use super::stata::dates::DateKind;
+use super::stata::Var;
+use super::text::parse_label_style;
+use super::translate::{parse_data_type, parse_date_handling, parse_missing_policy, VariableRule};
+
+/// A conversion spec file, in TOML
+///
+/// ```toml
+/// [parquet]
+/// compression = "zstd(6)"
+/// row_group_size = 1000000
+/// statistics = "page"
+/// byte_stream_split = true
+///
+/// [parquet.columns.income]
+/// encoding = "byte_stream_split"
+/// compression = "snappy"
+///
+/// [parquet.columns.pid]
+/// bloom_filter = true
+///
+/// [[variables]]
+/// match = "*_dt"
+/// dates = "native"
+///
+/// [[variables]]
+/// match = "tmp*"
+/// include = false
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Spec {
+    pub parquet: ParquetSpec,
+    pub names: NamesSpec,
+    pub variables: Vec<VariableSpec>,
+}
+
+/// The `[names]` table, with the settings of `NameTransform`
+///
+/// ```toml
+/// [names]
+/// snake_case = true
+/// replace = [["^q(\\d+)", "question_$1"]]
+///
+/// [names.map]
+/// hhid = "household_id"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamesSpec {
+    pub from_labels: bool,
+    pub max_length: Option<usize>,
+    pub lowercase: bool,
+    pub snake_case: bool,
+    /// Pairs of a regular expression and its replacement
+    pub replace: Vec<(String, String)>,
+    pub prefix: String,
+    pub suffix: String,
+    pub map: HashMap<String, String>,
+}
+
+/// The `[parquet]` table, with the settings of `ParquetOptions`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParquetSpec {
+    pub compression: Option<String>,
+    pub dictionary: Option<bool>,
+    pub data_page_size: Option<usize>,
+    pub row_group_size: Option<usize>,
+    pub statistics: Option<String>,
+    pub byte_stream_split: Option<bool>,
+    pub delta_integers: Option<bool>,
+    pub writer_version: Option<String>,
+    pub bloom_filter_fpp: Option<f64>,
+    pub columns: HashMap<String, ColumnSpec>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColumnSpec {
+    pub compression: Option<String>,
+    pub dictionary: Option<bool>,
+    pub encoding: Option<String>,
+    pub statistics: Option<String>,
+    pub bloom_filter: Option<bool>,
+    pub bloom_filter_fpp: Option<f64>,
+    pub bloom_filter_ndv: Option<u64>,
+}
+
+/// A `[[variables]]` entry, with the settings of `VariableRule`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VariableSpec {
+    /// Glob pattern of variable names
+    #[serde(rename = "match")]
+    pub pattern: String,
+    pub include: Option<bool>,
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub data_type: Option<String>,
+    pub dates: Option<String>,
+    pub value_labels: Option<String>,
+    pub missing: Option<String>,
+    pub compression: Option<String>,
+    pub encoding: Option<String>,
+}
+
+impl Spec {
+    pub fn load(path: &Path) -> Spec {
+        let text = fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        toml::from_str(&text).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+    }
+
+    /// The parquet writer settings of the spec, with defaults for the
+    /// settings it leaves out
+    pub fn parquet_options(&self) -> ParquetOptions {
+        let p = &self.parquet;
+        let defaults = ParquetOptions::default();
+        ParquetOptions {
+            compression: p.compression.as_deref().map_or(defaults.compression, |s| setting("compression", s, parse_compression)),
+            dictionary: p.dictionary.unwrap_or(defaults.dictionary),
+            data_page_size: p.data_page_size,
+            row_group_size: p.row_group_size,
+            statistics: p.statistics.as_deref().map(|s| setting("statistics", s, parse_statistics)),
+            byte_stream_split: p.byte_stream_split.unwrap_or(defaults.byte_stream_split),
+            delta_integers: p.delta_integers.unwrap_or(defaults.delta_integers),
+            writer_version: p.writer_version.as_deref().map(|s| setting("writer_version", s, parse_writer_version)),
+            bloom_filter_fpp: p.bloom_filter_fpp,
+            columns: p
+                .columns
+                .iter()
+                .map(|(name, c)| {
+                    let col = ColumnOptions {
+                        compression: c.compression.as_deref().map(|s| setting("compression", s, parse_compression)),
+                        dictionary: c.dictionary,
+                        encoding: c.encoding.as_deref().map(|s| setting("encoding", s, parse_encoding)),
+                        statistics: c.statistics.as_deref().map(|s| setting("statistics", s, parse_statistics)),
+                        bloom_filter: c.bloom_filter.unwrap_or(false),
+                        bloom_filter_fpp: c.bloom_filter_fpp,
+                        bloom_filter_ndv: c.bloom_filter_ndv,
+                    };
+                    (name.clone(), col)
+                })
+                .collect(),
+        }
+    }
+
+    /// The naming settings of the spec
+    pub fn name_transform(&self) -> NameTransform {
+        let n = &self.names;
+        NameTransform {
+            from_labels: n.from_labels,
+            max_length: n.max_length.unwrap_or(DEFAULT_MAX_LENGTH),
+            lowercase: n.lowercase,
+            snake_case: n.snake_case,
+            replace: n
+                .replace
+                .iter()
+                .map(|(pattern, with)| {
+                    let re = Regex::new(pattern).unwrap_or_else(|e| panic!("Spec setting replace = \"{}\": {}", pattern, e));
+                    (re, with.clone())
+                })
+                .collect(),
+            prefix: n.prefix.clone(),
+            suffix: n.suffix.clone(),
+            map: n.map.clone(),
+        }
+    }
+
+    /// The `[[variables]]` entries of the spec, in order
+    pub fn variable_rules(&self) -> Vec<VariableRule> {
+        self.variables
+            .iter()
+            .map(|v| VariableRule {
+                pattern: glob::Pattern::new(&v.pattern)
+                    .unwrap_or_else(|e| panic!("Spec setting match = \"{}\": {}", v.pattern, e)),
+                include: v.include,
+                name: v.name.clone(),
+                data_type: v
+                    .data_type
+                    .as_deref()
+                    .filter(|s| !s.eq_ignore_ascii_case("decimal"))
+                    .map(|s| setting("type", s, parse_data_type)),
+                decimal: v.data_type.as_deref().is_some_and(|s| s.eq_ignore_ascii_case("decimal")),
+                dates: v.dates.as_deref().map(|s| setting("dates", s, parse_date_handling)),
+                labels: v.value_labels.as_deref().map(|s| setting("value_labels", s, parse_label_style)),
+                missing: v.missing.as_deref().map(|s| setting("missing", s, parse_missing_policy)),
+                compression: v.compression.as_deref().map(|s| setting("compression", s, parse_compression)),
+                encoding: v.encoding.as_deref().map(|s| setting("encoding", s, parse_encoding)),
+            })
+            .collect()
+    }
+}
+
+/// A starter spec for a file with variables `vars`, with the parquet
+/// defaults commented out and an entry per variable to edit
+pub fn starter_spec(file: &str, vars: &[Var]) -> String {
+    let mut s = String::new();
+    writeln!(s, "# Conversion spec for {}", file).unwrap();
+    writeln!(s, "# Use with: dta2pqt --spec THIS_FILE in.dta out.parquet").unwrap();
+    writeln!(s, "# Later [[variables]] entries take precedence over earlier ones.").unwrap();
+    writeln!(s).unwrap();
+    writeln!(s, "[parquet]").unwrap();
+    writeln!(s, "# compression = \"snappy\"").unwrap();
+    writeln!(s, "# dictionary = true").unwrap();
+    writeln!(s, "# statistics = \"chunk\"").unwrap();
+    writeln!(s, "# row_group_size = 1048576").unwrap();
+    for v in vars {
+        writeln!(s).unwrap();
+        let mut about = format!("{} {}", v.ty, v.format);
+        if !v.value_label.is_empty() {
+            write!(about, ", labels {}", v.value_label).unwrap();
+        }
+        if !v.var_label.is_empty() {
+            write!(about, ": {}", v.var_label.replace(['\n', '\r'], " ")).unwrap();
+        }
+        writeln!(s, "# {}", about).unwrap();
+        writeln!(s, "[[variables]]").unwrap();
+        writeln!(s, "match = \"{}\"", v.name).unwrap();
+        writeln!(s, "include = true").unwrap();
+        writeln!(s, "# name = \"{}\"", v.name).unwrap();
+        if DateKind::from_format(&v.format).is_some() {
+            writeln!(s, "# dates = \"native\"").unwrap();
+        }
+        if !v.value_label.is_empty() {
+            writeln!(s, "# value_labels = \"text\"").unwrap();
+        }
+    }
+    s
+}
+
+fn setting<T>(key: &str, value: &str, parse: fn(&str) -> Result<T, &'static str>) -> T {
+    parse(value).unwrap_or_else(|e| panic!("Spec setting {} = \"{}\": {}", key, value, e))
+}
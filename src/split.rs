@@ -0,0 +1,304 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use arrow::datatypes::SchemaRef;
+use arrow_array::RecordBatch;
+use parquet::format::FileMetaData;
+use parquet::thrift::TSerializable;
+use thrift::protocol::{TCompactInputProtocol, TCompactOutputProtocol};
+
+use super::output::{check_overwrite, open_file_sink, write_file, BatchSink, OutputFormat, OutputOptions, STDOUT};
+use super::stata::Var;
+
+const PARQUET_MAGIC: &[u8] = b"PAR1";
+
+/// Name of the summary file written next to the parts
+pub const SUMMARY_FILE: &str = "_metadata";
+
+#[derive(Debug, Clone, Default)]
+pub struct SplitOptions {
+    /// Most rows in a file
+    pub max_rows: Option<usize>,
+    /// Start a new file once a file holds this many bytes
+    pub max_bytes: Option<u64>,
+    /// Write a parquet `_metadata` file holding the footers of all parts
+    pub summary: bool,
+}
+
+/// Writer of a sequence of files `out-00000.parquet`, `out-00001.parquet`, ...
+/// for the output path `out.parquet`
+///
+/// A new file is started when the current one reaches `max_rows`
+/// rows or `max_bytes` bytes. Bytes are only counted as the inner sink
+/// writes them out, so a file can go over `max_bytes` by up to a batch,
+/// or with parquet by up to a row group.
+pub struct SplitSink {
+    base: PathBuf,
+    schema: SchemaRef,
+    vars: Vec<Var>,
+    opts: OutputOptions,
+    split: SplitOptions,
+    current: Option<Box<dyn BatchSink>>,
+    rows_in_file: usize,
+    parts: Vec<PathBuf>,
+}
+
+impl SplitSink {
+    pub fn new(out_path: &Path, schema: SchemaRef, vars: &[Var], opts: &OutputOptions) -> SplitSink {
+        if out_path.as_os_str() == STDOUT {
+            panic!("Split output must go to files");
+        }
+        let split = opts.split.clone().unwrap();
+        if split.summary && opts.format != OutputFormat::Parquet {
+            panic!("A {} file can only be written for parquet output", SUMMARY_FILE);
+        }
+        if split.summary {
+            check_overwrite(&out_path.with_file_name(SUMMARY_FILE), opts.force);
+        }
+        if let Some(part) = existing_parts(out_path).first() {
+            check_overwrite(part, opts.force);
+        }
+        let mut opts = opts.clone();
+        opts.split = None;
+        SplitSink {
+            base: out_path.to_path_buf(),
+            schema,
+            vars: vars.to_vec(),
+            opts,
+            split,
+            current: None,
+            rows_in_file: 0,
+            parts: Vec::new(),
+        }
+    }
+
+    fn part_path(&self, i: usize) -> PathBuf {
+        let stem = self.base.file_stem().unwrap().to_string_lossy();
+        let name = match self.base.extension() {
+            Some(ext) => format!("{}-{:05}.{}", stem, i, ext.to_string_lossy()),
+            None => format!("{}-{:05}", stem, i),
+        };
+        self.base.with_file_name(name)
+    }
+
+    fn sink(&mut self) -> &mut Box<dyn BatchSink> {
+        if self.current.is_none() {
+            let path = self.part_path(self.parts.len());
+            self.current = Some(open_file_sink(&path, self.schema.clone(), &self.vars, &self.opts));
+            self.parts.push(path);
+            self.rows_in_file = 0;
+        }
+        self.current.as_mut().unwrap()
+    }
+
+    fn finish_part(&mut self) {
+        if let Some(s) = self.current.take() {
+            s.finish();
+        }
+    }
+}
+
+impl BatchSink for SplitSink {
+    fn write(&mut self, batch: &RecordBatch) {
+        let mut batch = batch.clone();
+        while batch.num_rows() > 0 {
+            let room = match self.split.max_rows {
+                Some(m) if self.current.is_some() => m - self.rows_in_file,
+                Some(m) => m,
+                None => batch.num_rows(),
+            };
+            let n = room.min(batch.num_rows());
+            self.sink().write(&batch.slice(0, n));
+            self.rows_in_file += n;
+            batch = batch.slice(n, batch.num_rows() - n);
+            let full_rows = self.split.max_rows.is_some_and(|m| self.rows_in_file >= m);
+            let full_bytes = self
+                .split
+                .max_bytes
+                .is_some_and(|m| self.current.as_ref().unwrap().bytes_written() >= m);
+            if full_rows || full_bytes {
+                self.finish_part();
+            }
+        }
+    }
+
+    fn finish(mut self: Box<Self>) {
+        if self.parts.is_empty() {
+            //Write the schema even if there are no rows
+            self.sink();
+        }
+        self.finish_part();
+        //Parts left by an earlier run that wrote more of them
+        for part in existing_parts(&self.base) {
+            if !self.parts.contains(&part) {
+                fs::remove_file(&part).unwrap_or_else(|e| panic!("{}: {}", part.display(), e));
+            }
+        }
+        if self.split.summary {
+            write_summary(&self.base.with_file_name(SUMMARY_FILE), &self.parts, self.opts.force);
+        }
+    }
+}
+
+/// A glob pattern matching the parts of the output `out_path`
+pub fn parts_pattern(out_path: &Path) -> String {
+    let stem = glob::Pattern::escape(&out_path.with_extension("").to_string_lossy());
+    match out_path.extension() {
+        Some(ext) => format!("{}-[0-9][0-9][0-9][0-9][0-9].{}", stem, glob::Pattern::escape(&ext.to_string_lossy())),
+        None => format!("{}-[0-9][0-9][0-9][0-9][0-9]", stem),
+    }
+}
+
+/// The parts of the output `out_path` that exist, in order
+pub fn existing_parts(out_path: &Path) -> Vec<PathBuf> {
+    glob::glob(&parts_pattern(out_path)).unwrap().map(|p| p.unwrap()).collect()
+}
+
+/// Write a parquet file holding no data but the row groups of all `parts`,
+/// with each column chunk pointing to its part
+fn write_summary(path: &Path, parts: &[PathBuf], force: bool) {
+    let mut summary: Option<FileMetaData> = None;
+    for part in parts {
+        let mut meta = read_footer(part);
+        let name = part.file_name().unwrap().to_string_lossy().into_owned();
+        for rg in &mut meta.row_groups {
+            for c in &mut rg.columns {
+                c.file_path = Some(name.clone());
+            }
+        }
+        match &mut summary {
+            None => summary = Some(meta),
+            Some(s) => {
+                s.num_rows += meta.num_rows;
+                s.row_groups.extend(meta.row_groups);
+            }
+        }
+    }
+    let summary = summary.unwrap();
+    let mut footer = Vec::new();
+    summary
+        .write_to_out_protocol(&mut TCompactOutputProtocol::new(&mut footer))
+        .unwrap();
+    let mut out = Vec::with_capacity(footer.len() + 12);
+    out.extend_from_slice(PARQUET_MAGIC);
+    out.extend_from_slice(&footer);
+    out.extend_from_slice(&(footer.len() as u32).to_le_bytes());
+    out.extend_from_slice(PARQUET_MAGIC);
+    write_file(path, &out, force);
+}
+
+fn read_footer(path: &Path) -> FileMetaData {
+    let mut f = File::open(path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    let mut tail = [0u8; 8];
+    f.seek(SeekFrom::End(-8)).unwrap();
+    f.read_exact(&mut tail).unwrap();
+    let len = u32::from_le_bytes(tail[..4].try_into().unwrap()) as i64;
+    let mut footer = vec![0u8; len as usize];
+    f.seek(SeekFrom::End(-8 - len)).unwrap();
+    f.read_exact(&mut footer).unwrap();
+    FileMetaData::read_from_in_protocol(&mut TCompactInputProtocol::new(&footer[..])).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use arrow::array::Int32Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    use crate::parquet::ParquetOptions;
+    use crate::rename::NameTransform;
+    use crate::text::TextOptions;
+    use crate::translate::Widening;
+
+    fn opts(max_rows: usize, force: bool) -> OutputOptions {
+        OutputOptions {
+            format: OutputFormat::Parquet,
+            ipc_compression: None,
+            text: TextOptions::default(),
+            force,
+            partition: None,
+            split: Some(SplitOptions { max_rows: Some(max_rows), max_bytes: None, summary: true }),
+            verify_sort: false,
+            optimize: false,
+            sort: None,
+            parquet: ParquetOptions::default(),
+            variables: Vec::new(),
+            names: NameTransform::default(),
+            widening: Widening::default(),
+            label_tables: false,
+            datapackage: false,
+        }
+    }
+
+    /// Write rows 0 to 9 to `out_path` split by `opts`
+    fn write(out_path: &Path, opts: &OutputOptions) {
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from_iter_values(0..10))]).unwrap();
+        let mut sink = Box::new(SplitSink::new(out_path, schema, &[], opts));
+        sink.write(&batch);
+        sink.finish();
+    }
+
+    /// The file and number of rows of each row group of the `_metadata` file
+    /// next to `out_path`, and its total number of rows
+    fn summary(out_path: &Path) -> (Vec<(String, i64)>, i64) {
+        let file = File::open(out_path.with_file_name(SUMMARY_FILE)).unwrap();
+        let reader = SerializedFileReader::new(file).unwrap();
+        let meta = reader.metadata();
+        let row_groups = meta
+            .row_groups()
+            .iter()
+            .map(|rg| (rg.column(0).file_path().unwrap().to_string(), rg.num_rows()))
+            .collect();
+        (row_groups, meta.file_metadata().num_rows())
+    }
+
+    fn names(paths: &[PathBuf]) -> Vec<String> {
+        paths.iter().map(|p| p.file_name().unwrap().to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn summary_of_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_path = dir.path().join("out.parquet");
+        write(&out_path, &opts(4, false));
+        let parts = existing_parts(&out_path);
+        assert_eq!(names(&parts), ["out-00000.parquet", "out-00001.parquet", "out-00002.parquet"]);
+        let (row_groups, rows) = summary(&out_path);
+        assert_eq!(
+            row_groups,
+            [
+                ("out-00000.parquet".to_string(), 4),
+                ("out-00001.parquet".to_string(), 4),
+                ("out-00002.parquet".to_string(), 2)
+            ]
+        );
+        assert_eq!(rows, 10);
+    }
+
+    #[test]
+    fn rerun_removes_stale_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_path = dir.path().join("out.parquet");
+        write(&out_path, &opts(2, false));
+        assert_eq!(existing_parts(&out_path).len(), 5);
+        write(&out_path, &opts(5, true));
+        assert_eq!(names(&existing_parts(&out_path)), ["out-00000.parquet", "out-00001.parquet"]);
+        let (row_groups, rows) = summary(&out_path);
+        assert_eq!(row_groups, [("out-00000.parquet".to_string(), 5), ("out-00001.parquet".to_string(), 5)]);
+        assert_eq!(rows, 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "use --force")]
+    fn rerun_needs_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_path = dir.path().join("out.parquet");
+        write(&out_path, &opts(4, false));
+        write(&out_path, &opts(4, false));
+    }
+}
@@ -1,8 +1,10 @@
+use std::fmt;
 use std::sync::Arc;
 
 pub mod values;
 pub mod file;
 pub mod error;
+pub mod dates;
 
 #[derive(Debug,Clone, Copy)]
 pub enum VarType {
@@ -16,7 +18,23 @@ pub enum VarType {
     TDouble,
 }
 
-#[derive(Debug)]
+impl fmt::Display for VarType {
+    /// The Stata name of the type, as in `str12` or `double`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VarType::TStrf(n) => write!(f, "str{}", n),
+            VarType::TASCII(n) => write!(f, "str{}", n),
+            VarType::TStrl => write!(f, "strL"),
+            VarType::TByte => write!(f, "byte"),
+            VarType::TInt => write!(f, "int"),
+            VarType::TLong => write!(f, "long"),
+            VarType::TFloat => write!(f, "float"),
+            VarType::TDouble => write!(f, "double"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Var {
     pub ty: VarType,
     pub name: String,
@@ -29,6 +47,15 @@ pub struct Var {
 pub struct ValueLabelTable {
     pub labelname: String,
     pub labels: Vec<String>,
-    pub values: Vec<u32>,
+    pub values: Vec<i32>,
+}
+
+impl ValueLabelTable {
+    pub fn label(&self, value: i32) -> Option<&str> {
+        self.values
+            .iter()
+            .position(|&v| v == value)
+            .map(|i| self.labels[i].as_str())
+    }
 }
 
@@ -0,0 +1,141 @@
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike};
+
+/// The kind of date a Stata display format (`%td`, `%tm`, ...) stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateKind {
+    /// `%td`: days since 01jan1960
+    Day,
+    /// `%tc` and `%tC`: milliseconds since 01jan1960 00:00:00.
+    /// Leap seconds in `%tC` are ignored.
+    Millis,
+    /// `%tw`: weeks since 1960w1, 52 weeks a year
+    Week,
+    /// `%tm`: months since 1960m1
+    Month,
+    /// `%tq`: quarters since 1960q1
+    Quarter,
+    /// `%th`: half years since 1960h1
+    HalfYear,
+    /// `%ty`: the year itself
+    Year,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateStyle {
+    /// ISO 8601 where it exists: `2020-01-31`, `2020-01-31T12:00:00.000`, `2020-01`
+    Iso,
+    /// Stata's default display: `31jan2020`, `31jan2020 12:00:00.000`, `2020m1`
+    Stata,
+    /// The number as stored
+    Raw,
+}
+
+impl DateKind {
+    /// Recognise a Stata date format. `%d` is the pre-Stata 10 spelling of `%td`.
+    /// Any display details after the type letters are ignored.
+    pub fn from_format(format: &str) -> Option<DateKind> {
+        let f = format.strip_prefix('%')?;
+        let f = f.strip_prefix('-').unwrap_or(f);
+        if f.starts_with('d') {
+            return Some(DateKind::Day);
+        }
+        let f = f.strip_prefix('t')?;
+        match f.chars().next()? {
+            'd' => Some(DateKind::Day),
+            'c' | 'C' => Some(DateKind::Millis),
+            'w' => Some(DateKind::Week),
+            'm' => Some(DateKind::Month),
+            'q' => Some(DateKind::Quarter),
+            'h' => Some(DateKind::HalfYear),
+            'y' => Some(DateKind::Year),
+            _ => None,
+        }
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+pub fn stata_epoch() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(1960, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap()
+}
+
+/// Render the stored value `x` of a date variable. Days and times
+/// beyond the dates chrono handles are rendered as numbers.
+pub fn render_date(kind: DateKind, x: f64, style: DateStyle) -> String {
+    if style == DateStyle::Raw || !x.is_finite() {
+        return format!("{}", x);
+    }
+    let n = x.floor() as i64;
+    match kind {
+        DateKind::Day => {
+            let Some(d) = Duration::try_days(n).and_then(|t| stata_epoch().checked_add_signed(t)) else {
+                return format!("{}", x);
+            };
+            match style {
+                DateStyle::Iso => d.format("%Y-%m-%d").to_string(),
+                _ => format!("{:02}{}{}", d.day(), MONTHS[d.month0() as usize], d.year()),
+            }
+        }
+        DateKind::Millis => {
+            let Some(d) = Duration::try_milliseconds(n).and_then(|t| stata_epoch().checked_add_signed(t)) else {
+                return format!("{}", x);
+            };
+            match style {
+                DateStyle::Iso => d.format("%Y-%m-%dT%H:%M:%S%.3f").to_string(),
+                _ => format!(
+                    "{:02}{}{} {:02}:{:02}:{:02}.{:03}",
+                    d.day(),
+                    MONTHS[d.month0() as usize],
+                    d.year(),
+                    d.hour(),
+                    d.minute(),
+                    d.second(),
+                    d.nanosecond() / 1_000_000
+                ),
+            }
+        }
+        DateKind::Week => format!("{}w{}", 1960 + n.div_euclid(52), n.rem_euclid(52) + 1),
+        DateKind::Month => {
+            let (y, m) = (1960 + n.div_euclid(12), n.rem_euclid(12) + 1);
+            match style {
+                DateStyle::Iso => format!("{}-{:02}", y, m),
+                _ => format!("{}m{}", y, m),
+            }
+        }
+        DateKind::Quarter => {
+            let (y, q) = (1960 + n.div_euclid(4), n.rem_euclid(4) + 1);
+            match style {
+                DateStyle::Iso => format!("{}-Q{}", y, q),
+                _ => format!("{}q{}", y, q),
+            }
+        }
+        DateKind::HalfYear => {
+            let (y, h) = (1960 + n.div_euclid(2), n.rem_euclid(2) + 1);
+            match style {
+                DateStyle::Iso => format!("{}-H{}", y, h),
+                _ => format!("{}h{}", y, h),
+            }
+        }
+        DateKind::Year => format!("{}", n),
+    }
+}
+
+/// The first day of the period `n` of a date variable that is not `%tc`
+pub fn period_start(kind: DateKind, n: i64) -> Option<NaiveDate> {
+    let first_of = |y: i64, m: i64| NaiveDate::from_ymd_opt(i32::try_from(y).ok()?, m as u32, 1);
+    match kind {
+        DateKind::Day => stata_epoch().date().checked_add_signed(Duration::try_days(n)?),
+        DateKind::Millis => None,
+        DateKind::Week => {
+            //Week 52 takes up the rest of the year
+            let w = n.rem_euclid(52);
+            first_of(1960 + n.div_euclid(52), 1)?.checked_add_signed(Duration::try_days(7 * w)?)
+        }
+        DateKind::Month => first_of(1960 + n.div_euclid(12), n.rem_euclid(12) + 1),
+        DateKind::Quarter => first_of(1960 + n.div_euclid(4), 3 * n.rem_euclid(4) + 1),
+        DateKind::HalfYear => first_of(1960 + n.div_euclid(2), 6 * n.rem_euclid(2) + 1),
+        DateKind::Year => first_of(n, 1),
+    }
+}
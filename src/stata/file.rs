@@ -1,21 +1,21 @@
-use std::{iter::zip, sync::Arc};
+use std::{collections::BTreeMap, iter::zip, sync::Arc};
 
 use arrow_array::builder::{
     make_builder, ArrayBuilder, BinaryBuilder, Float32Builder, Float64Builder, Int16Builder, Int32Builder, Int8Builder, StringBuilder
 };
+use arrow::datatypes::DataType;
+use arrow_array::{new_null_array, ArrayRef, RecordBatch};
 use nom::{
     bytes::complete::{tag, take},
     multi::{many0, many_m_n},
     number::{
-        complete::{le_u16, le_u32, le_u8, u8},
+        complete::{le_i32, le_u16, le_u32, le_u8, u8},
         streaming::le_u64,
     },
     IResult,
 };
-use parquet::arrow::arrow_writer::{compute_leaves, ArrowLeafColumn};
-
 use super::values::{
-    bytes_to_string, parse_byte, parse_double, parse_float, parse_int, parse_long, parse_strlid,
+    bytes_to_string, missing_code_str, parse_byte, parse_double, parse_float, parse_int, parse_long, parse_strlid, Value,
 };
 use super::{
     error::{DownstreamError, Error},
@@ -23,7 +23,7 @@ use super::{
 };
 use super::{Var, VarType};
 
-use super::super::translate::make_schema;
+use super::super::translate::{make_schema, make_schema_with_missing_codes, missing_code_field};
 
 #[derive(Debug)]
 pub enum ByteOrder {
@@ -36,11 +36,22 @@ pub struct Metadata {
     pub byteorder: ByteOrder,
     pub nvars: usize,
     pub nobs: usize,
+    /// The dataset label
+    pub data_label: String,
     pub vars: Vec<Var>,
     pub rowsize: usize,
     pub datasize: usize,
+    pub value_labels: Vec<Arc<ValueLabelTable>>,
+    /// Indices of the variables the data is sorted by
+    pub sortlist: Vec<usize>,
+    pub characteristics: Characteristics,
 }
 
+/// Characteristics by the name they are attached to, `_dta` for the
+/// dataset or a variable name, and then by their own name. Notes are
+/// the characteristics `note1`, `note2` and so on, counted by `note0`.
+pub type Characteristics = BTreeMap<String, BTreeMap<String, String>>;
+
 pub struct FileMap<'a> {
     pub data_buf: &'a [u8],
     pub value_labels_buf: &'a [u8],
@@ -55,19 +66,46 @@ pub struct StrlEntry<'a> {
     pub s: &'a [u8]
 }
 
-pub fn parse_metadata(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
+pub fn parse_metadata(input: &[u8]) -> Result<(Metadata, FileMap<'_>), Error> {
     let (_, version) = u8(input).map_res("version")?;
-    if version == 113 || version == 114 {
-        parse_metadata_old(&input)
+    let (mut metadata, file_map) = if version == 113 || version == 114 {
+        parse_metadata_old(input)?
     } else if input[0] == b'<' {
-        parse_metadata_new(&input)
+        parse_metadata_new(input)?
     } else {
         panic!("Unsupported version")
+    };
+    let (_, tables) = if metadata.version >= 117 {
+        parse_value_labels_newstyle(file_map.value_labels_buf, metadata.version)
+    } else {
+        parse_value_labels_oldstyle(file_map.value_labels_buf)
+    }.map_res("value labels")?;
+    attach_value_labels(&mut metadata, tables);
+    Ok((metadata, file_map))
+}
+
+/// Point each variable's `dictionary` at its value label table
+pub fn attach_value_labels(meta: &mut Metadata, tables: Vec<Arc<ValueLabelTable>>) {
+    for v in meta.vars.iter_mut() {
+        v.dictionary = tables
+            .iter()
+            .find(|t| !v.value_label.is_empty() && t.labelname == v.value_label)
+            .cloned();
     }
+    meta.value_labels = tables;
 }
 
-pub fn parse_metadata_new(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
-    let start = input;
+/// The `<header>` and `<map>` of a DTA 117+ file
+pub struct HeaderNew {
+    pub version: u8,
+    pub byteorder: ByteOrder,
+    pub nvars: usize,
+    pub nobs: usize,
+    pub data_label: String,
+    pub file_offsets: Vec<u64>,
+}
+
+pub fn parse_header_new(input: &[u8]) -> Result<(&[u8], HeaderNew), Error> {
     let input = parse_tag(input, b"<stata_dta>")?;
     let input = parse_tag(input, b"<header>")?;
     let input = parse_tag(input, b"<release>")?;
@@ -109,7 +147,7 @@ pub fn parse_metadata_new(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
     let input = parse_tag(input, b"<label>")?;
     let (input, ll) = le_u16(input).map_res("dataset label length")?;
     let (input, dataset_label) = take(ll as usize)(input).map_res("dataset label")?;
-    let _dataset_label = bytes_to_string(dataset_label);
+    let data_label = bytes_to_string(dataset_label);
     let input = parse_tag(input, b"</label>")?;
 
     let input = parse_tag(input, b"<timestamp>")?;
@@ -124,6 +162,16 @@ pub fn parse_metadata_new(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
     let (input, file_offsets) = many_m_n(14usize, 14usize, le_u64)(input).map_res("map")?;
     let input = parse_tag(input, b"</map>")?;
 
+    Ok((input, HeaderNew { version, byteorder, nvars, nobs, data_label, file_offsets }))
+}
+
+/// Parse the metadata of a DTA 117+ file, returning it with the
+/// offsets of the file's sections. Only the part of the file
+/// before `<data>` is needed.
+pub fn parse_head_new(input: &[u8]) -> Result<(Metadata, Vec<u64>), Error> {
+    let start = input;
+    let (input, HeaderNew { version, byteorder, nvars, nobs, data_label, file_offsets }) = parse_header_new(input)?;
+
     let input = parse_tag(input, b"<variable_types>")?;
     let (input, tycodes) = many_m_n(nvars, nvars, le_u16)(input).map_res("variable_types")?;
     let input = parse_tag(input, b"</variable_types>")?;
@@ -133,7 +181,7 @@ pub fn parse_metadata_new(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
     let input = parse_tag(input, b"</varnames>")?;
 
     let input = parse_tag(input, b"<sortlist>")?;
-    let (input, _srtlist) = many_m_n(nvars + 1, nvars + 1, le_u16)(input).map_res("sortlist")?;
+    let (input, srtlist) = many_m_n(nvars + 1, nvars + 1, le_u16)(input).map_res("sortlist")?;
     let input = parse_tag(input, b"</sortlist>")?;
 
     let input = parse_tag(input, b"<formats>")?;
@@ -150,10 +198,19 @@ pub fn parse_metadata_new(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
         many_m_n(nvars, nvars, take(321usize))(input).map_res("value_label_names")?;
     let _ = parse_tag(input, b"</variable_labels>")?;
 
+    let name_len = if version == 117 { 33usize } else { 129usize };
+    let input = start
+        .get(file_offsets[8] as usize..)
+        .ok_or_else(|| Error::ParseError(String::from("characteristics offset")))?;
+    let input = parse_tag(input, b"<characteristics>")?;
+    let (input, chars) =
+        many0(|i| parse_characteristic_new(i, name_len))(input).map_res("characteristics")?;
+    let _ = parse_tag(input, b"</characteristics>")?;
+
     let mut vars: Vec<Var> = Vec::with_capacity(nvars);
     for i in 0..nvars {
         let tcode = tycodes[i];
-        let ty = if tcode >= 1 && tcode <= 2045 {
+        let ty = if (1..=2045).contains(&tcode) {
             VarType::TStrf(tcode)
         } else if tcode == 32768 {
             VarType::TStrl
@@ -171,7 +228,7 @@ pub fn parse_metadata_new(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
             panic!("Unknown tcode {},{}", i, tcode)
         };
         vars.push(Var {
-            ty: ty,
+            ty,
             name: bytes_to_string(names[i]),
             format: bytes_to_string(fmtlist[i]),
             value_label: bytes_to_string(flbllist[i]),
@@ -181,25 +238,38 @@ pub fn parse_metadata_new(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
     }
     let rowsize = calculate_rowsize(&vars);
     let datasize = rowsize * nobs;
-    let data_start = (file_offsets[9]+6) as usize; //Skip <data>
-    let data_end = (file_offsets[10]-7) as usize; //Skip </data>
-
-    let labels_start = (file_offsets[11]+14) as usize; //Skip <value_labels>
-    let labels_end = (file_offsets[12]-15) as usize; //Skip </value_labels>
-
-    let strls_start = (file_offsets[10]+7) as usize;
-    let strls_end = (file_offsets[11]-8) as usize;
-
     Ok((
         Metadata {
             version,
             byteorder,
             nvars,
             nobs,
+            data_label,
             vars,
             rowsize,
             datasize,
+            value_labels: Vec::new(),
+            sortlist: sortlist_vars(&srtlist, nvars),
+            characteristics: collect_characteristics(chars),
         },
+        file_offsets,
+    ))
+}
+
+pub fn parse_metadata_new(input: &[u8]) -> Result<(Metadata, FileMap<'_>), Error> {
+    let start = input;
+    let (metadata, file_offsets) = parse_head_new(input)?;
+    let data_start = (file_offsets[9]+6) as usize; //Skip <data>
+    let data_end = (file_offsets[10]-7) as usize; //Skip </data>
+
+    let labels_start = (file_offsets[11]+14) as usize; //Skip <value_labels>
+    let labels_end = (file_offsets[12]-15) as usize; //Skip </value_labels>
+
+    let strls_start = (file_offsets[10]+7) as usize;
+    let strls_end = (file_offsets[11]-8) as usize;
+
+    Ok((
+        metadata,
         FileMap {
             data_buf: &start[data_start..data_end],
             value_labels_buf: &start[labels_start..labels_end],
@@ -207,16 +277,35 @@ pub fn parse_metadata_new(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
         },))
 }
 
+/// A `<ch>` entry: the name it is attached to, its own name and contents
+fn parse_characteristic_new(input: &[u8], name_len: usize) -> IResult<&[u8], (String, String, String)> {
+    let (input, _) = tag(b"<ch>")(input)?;
+    let (input, len) = le_u32(input)?;
+    let (input, varname) = take(name_len)(input)?;
+    let (input, charname) = take(name_len)(input)?;
+    let (input, contents) = take((len as usize).saturating_sub(2 * name_len))(input)?;
+    let (input, _) = tag(b"</ch>")(input)?;
+    Ok((input, (bytes_to_string(varname), bytes_to_string(charname), bytes_to_string(contents))))
+}
+
+fn collect_characteristics(chars: Vec<(String, String, String)>) -> Characteristics {
+    let mut out = Characteristics::new();
+    for (varname, charname, contents) in chars {
+        out.entry(varname).or_default().insert(charname, contents);
+    }
+    out
+}
+
 fn parse_tag<'a>(input: &'a [u8], tag_dat: &[u8]) -> Result<&'a [u8], Error> {
     let (input, _) = tag(tag_dat)(input).map_res(&format!("{:?}", String::from_utf8(tag_dat.to_vec())))?;
     Ok(input)
 }
 
-pub fn parse_strls(input: &[u8]) -> Result<Vec<StrlEntry>,Error>{
+pub fn parse_strls(input: &[u8]) -> Result<Vec<StrlEntry<'_>>,Error>{
     let (_,tab) = many0(parse_strl)(input).map_res("strl table")?;
     Ok(tab)
 }
-fn parse_strl(input: &[u8]) -> IResult<&[u8],StrlEntry> {
+fn parse_strl(input: &[u8]) -> IResult<&[u8],StrlEntry<'_>> {
     let (input,_) = tag(b"GSO")(input)?;
     let (input,v) = le_u32(input)?;
     let (input,o) = le_u64(input)?;
@@ -226,7 +315,7 @@ fn parse_strl(input: &[u8]) -> IResult<&[u8],StrlEntry> {
     Ok((input,StrlEntry{v,o,is_string: t==130,s}))
 
 }
-pub fn parse_metadata_old(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
+pub fn parse_metadata_old(input: &[u8]) -> Result<(Metadata, FileMap<'_>), Error> {
     let (input, version) = u8(input).map_res("version")?;
     let (input, byteorder) = u8(input).map_res("byteorder")?;
     if byteorder != 0x02 {
@@ -239,12 +328,14 @@ pub fn parse_metadata_old(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
     let nvars = nvars as usize;
     let (input, nobs) = le_u32(input).map_res("nobs")?;
     let nobs = nobs as usize;
-    //Ignore data label and timestamp
-    let (input, _) = take(99usize)(input).map_res("pad")?;
+    let (input, data_label) = take(81usize)(input).map_res("data label")?;
+    let data_label = bytes_to_string(data_label);
+    //Ignore the timestamp
+    let (input, _) = take(18usize)(input).map_res("pad")?;
 
     let (input, tycodes) = many_m_n(nvars, nvars, u8)(input).map_res("typecodes")?;
     let (input, names) = many_m_n(nvars, nvars, take(33usize))(input).map_res("varnames")?;
-    let (input, _) = many_m_n(nvars + 1, nvars + 1, le_u16)(input).map_res("sortlist")?;
+    let (input, srtlist) = many_m_n(nvars + 1, nvars + 1, le_u16)(input).map_res("sortlist")?;
     let (input, fmtlist) = many_m_n(
         nvars,
         nvars,
@@ -257,7 +348,7 @@ pub fn parse_metadata_old(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
     let mut vars: Vec<Var> = Vec::with_capacity(nvars);
     for i in 0..nvars {
         let tcode = tycodes[i];
-        let ty = if tcode >= 1 && tcode <= 244 {
+        let ty = if (1..=244).contains(&tcode) {
             VarType::TASCII(tcode)
         } else if tcode == 251 {
             VarType::TByte
@@ -273,7 +364,7 @@ pub fn parse_metadata_old(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
             panic!("Unknown tcode {},{}", i, tcode)
         };
         vars.push(Var {
-            ty: ty,
+            ty,
             name: bytes_to_string(names[i]),
             format: bytes_to_string(fmtlist[i]),
             value_label: bytes_to_string(flbllist[i]),
@@ -282,17 +373,26 @@ pub fn parse_metadata_old(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
         });
     }
 
-    //Skip expansion fields
+    //Expansion fields of type 1 are characteristics, others are skipped
     let mut input = input;
     let mut dtype;
     let mut len;
+    let mut chars = Vec::new();
     loop {
         (input, dtype) = u8(input).map_res("xfield type")?;
         (input, len) = le_u32(input).map_res("xfield len")?;
         if dtype == 0 {
             break;
         }
-        (input, _) = take(len as usize)(input).map_res("xfield")?;
+        let field;
+        (input, field) = take(len as usize)(input).map_res("xfield")?;
+        if dtype == 1 && field.len() >= 66 {
+            chars.push((
+                bytes_to_string(&field[..33]),
+                bytes_to_string(&field[33..66]),
+                bytes_to_string(&field[66..]),
+            ));
+        }
     }
     let rowsize = calculate_rowsize(&vars);
     let datasize = rowsize * nobs;
@@ -302,9 +402,13 @@ pub fn parse_metadata_old(input: &[u8]) -> Result<(Metadata, FileMap), Error> {
             byteorder,
             nvars,
             nobs,
+            data_label,
             vars,
             rowsize,
             datasize,
+            value_labels: Vec::new(),
+            sortlist: sortlist_vars(&srtlist, nvars),
+            characteristics: collect_characteristics(chars),
         },
         FileMap {
             data_buf: &input[..datasize],
@@ -320,21 +424,40 @@ pub fn parse_data(
     strl_tab: &Vec<StrlEntry>,
     start_row: usize,
     end_row: usize,
-) -> Result<Vec<ArrowLeafColumn>, Error> {
+    missing_codes: bool,
+    parsed: &[bool],
+) -> Result<RecordBatch, Error> {
     let mut buf = &file_map.data_buf[(start_row * meta.rowsize)..(end_row * meta.rowsize)];
-    let mut builders: Vec<Box<dyn ArrayBuilder>> = Vec::new();
-    let schema = make_schema(&meta.vars);
+    //Variables not in `parsed` are skipped, and left null
+    let mut builders: Vec<Option<Box<dyn ArrayBuilder>>> = Vec::new();
+    let schema = Arc::new(if missing_codes {
+        make_schema_with_missing_codes(&meta.vars)
+    } else {
+        make_schema(&meta.vars)
+    });
 
 
-    for f in schema.fields() {
-        builders.push(make_builder(f.data_type(), meta.nobs));
+    for (f, &p) in zip(schema.fields().iter().take(meta.nvars), parsed) {
+        builders.push(Some(make_builder(f.data_type(), end_row - start_row)).filter(|_| p));
     }
+    //One builder of missing codes per variable, None for strings or if not asked for
+    let mut code_builders: Vec<Option<StringBuilder>> = zip(&meta.vars, parsed)
+        .map(|(v, &p)| {
+            missing_code_field(v)
+                .filter(|_| missing_codes && p)
+                .map(|_| StringBuilder::new())
+        })
+        .collect();
     for _ in 0..(end_row - start_row) {
         //        if (i>10) {
         //            break;
         //        }
         //        println!("Obs {}",i);
-        for (f, b) in zip(&meta.vars, &mut builders) {
+        for ((f, b), mc) in zip(zip(&meta.vars, &mut builders), &mut code_builders) {
+            let Some(b) = b else {
+                buf = &buf[var_width(f)..];
+                continue;
+            };
             match f.ty {
                 VarType::TByte => {
                     let d;
@@ -348,6 +471,7 @@ pub fn parse_data(
                         Ok (v) => {b.append_value(v);}
                         Err (_) => {b.append_null();}
                     };
+                    push_missing_code(mc, &d);
                 }
                 VarType::TInt => {
                     let d;
@@ -361,6 +485,7 @@ pub fn parse_data(
                         Ok (v) => {b.append_value(v);}
                         Err (_) => {b.append_null();}
                     };
+                    push_missing_code(mc, &d);
                 }
                 VarType::TLong => {
                     let d;
@@ -374,6 +499,7 @@ pub fn parse_data(
                         Ok (v) => {b.append_value(v);}
                         Err (_) => {b.append_null();}
                     };
+                    push_missing_code(mc, &d);
                 }
                 VarType::TFloat => {
                     let d;
@@ -387,6 +513,7 @@ pub fn parse_data(
                         Ok (v) => {b.append_value(v);}
                         Err (_) => {b.append_null();}
                     };
+                    push_missing_code(mc, &d);
                     
                 }
                 VarType::TDouble => {
@@ -401,11 +528,11 @@ pub fn parse_data(
                         Ok (v) => {b.append_value(v);}
                         Err (_) => {b.append_null();}
                     };
+                    push_missing_code(mc, &d);
                 }
                 VarType::TASCII(n) => {
-                    let d: String;
                     let n = n as usize;
-                    d = bytes_to_string(&buf[..n]);
+                    let d = bytes_to_string(&buf[..n]);
                     //                    print!("{}, ",d);
                     buf = &buf[n..];
                     b.as_any_mut()
@@ -414,9 +541,8 @@ pub fn parse_data(
                         .append_value(d);
                 }
                 VarType::TStrf(n) => {
-                    let d: String;
                     let n = n as usize;
-                    d = bytes_to_string(&buf[..n]);
+                    let d = bytes_to_string(&buf[..n]);
                     //                    print!("{}, ",d);
                     buf = &buf[n..];
                     b.as_any_mut()
@@ -433,7 +559,7 @@ pub fn parse_data(
                     } else {
                         let strl_idx = strl_tab
                                         .binary_search_by_key(&ov,|s| {(s.o,s.v)})
-                                        .expect(&format!("{ov:?} vo entry not found"));
+                                        .unwrap_or_else(|_| panic!("{ov:?} vo entry not found"));
                         strl_tab[strl_idx].s
                     };
                     b.as_any_mut()
@@ -445,62 +571,109 @@ pub fn parse_data(
         }
         //        print!("\n\n\n");
     }
-    let to_write = builders
-        .into_iter()
-        .zip(&schema.fields)
-        .map(|(mut builder, field)| {
-            let arr = builder.as_mut().finish();
-            return compute_leaves(field, &arr).unwrap();
+    let nrows = end_row - start_row;
+    let mut columns: Vec<ArrayRef> = zip(builders, schema.fields())
+        .map(|(builder, f)| match builder {
+            Some(mut b) => b.as_mut().finish(),
+            None => new_null_array(f.data_type(), nrows),
         })
-        .flatten()
         .collect();
-    Ok(to_write)
+    if missing_codes {
+        columns.extend(
+            zip(&meta.vars, code_builders)
+                .filter(|(v, _)| missing_code_field(v).is_some())
+                .map(|(_, b)| match b {
+                    Some(mut b) => Arc::new(b.finish()) as ArrayRef,
+                    None => new_null_array(&DataType::Utf8, nrows),
+                }),
+        );
+    }
+    Ok(RecordBatch::try_new(schema, columns).unwrap())
+}
+
+fn push_missing_code<T>(b: &mut Option<StringBuilder>, d: &Value<T>) {
+    if let Some(b) = b {
+        match d {
+            Ok(_) => b.append_null(),
+            Err(code) => b.append_value(missing_code_str(*code)),
+        }
+    }
 }
 
 
 pub fn parse_value_labels_oldstyle(input: &[u8]) -> IResult<&[u8], Vec<Arc<ValueLabelTable>>> {
     let (input, tables) = many0(parse_one_value_label_table_oldstyle)(input)?;
-    return Ok((input, tables));
+    Ok((input, tables))
 }
 
 pub fn parse_one_value_label_table_oldstyle(input: &[u8]) -> IResult<&[u8], Arc<ValueLabelTable>> {
     let (input, _) = take(4usize)(input)?;
     let (input, labname) = take(33usize)(input)?;
     let (input, _) = take(3usize)(input)?;
+    parse_value_label_table(input, labname)
+}
+
+pub fn parse_value_labels_newstyle(input: &[u8], version: u8) -> IResult<&[u8], Vec<Arc<ValueLabelTable>>> {
+    many0(|i| parse_one_value_label_table_newstyle(i, version))(input)
+}
+
+pub fn parse_one_value_label_table_newstyle(input: &[u8], version: u8) -> IResult<&[u8], Arc<ValueLabelTable>> {
+    let (input, _) = tag(b"<lbl>")(input)?;
+    let (input, _) = take(4usize)(input)?;
+    let (input, labname) = take(if version == 117 { 33usize } else { 129usize })(input)?;
+    let (input, _) = take(3usize)(input)?;
+    let (input, table) = parse_value_label_table(input, labname)?;
+    let (input, _) = tag(b"</lbl>")(input)?;
+    Ok((input, table))
+}
+
+fn parse_value_label_table<'a>(input: &'a [u8], labname: &[u8]) -> IResult<&'a [u8], Arc<ValueLabelTable>> {
     let (input, n) = le_u32(input)?;
     let n = n as usize;
     let (input, txtlen) = le_u32(input)?;
     let txtlen = txtlen as usize;
     let (input, offsets) = many_m_n(n, n, le_u32)(input)?;
-    let (input, values) = many_m_n(n, n, le_u32)(input)?;
+    let (input, values) = many_m_n(n, n, le_i32)(input)?;
     let (input, txt) = take(txtlen)(input)?;
-    let mut labels: Vec<String> = Vec::new();
-    for i in 0..n {
-        let o = offsets[i] as usize;
-        let s = bytes_to_string(&txt[o..]);
-        labels.push(s);
-    }
-    return Ok((
+    let labels: Vec<String> = offsets
+        .iter()
+        .map(|&o| bytes_to_string(&txt[o as usize..]))
+        .collect();
+    Ok((
         input,
         Arc::new(ValueLabelTable {
             labelname: bytes_to_string(labname),
             labels,
             values,
         }),
-    ));
+    ))
 }
 
 fn calculate_rowsize(vars: &[Var]) -> usize {
-    vars.iter()
-        .map(|v| match v.ty {
-            VarType::TASCII(n) => n as usize,
-            VarType::TStrf(n) => n as usize,
-            VarType::TStrl => 8usize,
-            VarType::TByte => 1usize,
-            VarType::TInt => 2usize,
-            VarType::TLong => 4usize,
-            VarType::TFloat => 4usize,
-            VarType::TDouble => 8usize,
-        })
-        .sum()
+    vars.iter().map(var_width).sum()
+}
+
+/// Bytes a variable takes up in a row
+fn var_width(v: &Var) -> usize {
+    match v.ty {
+        VarType::TASCII(n) => n as usize,
+        VarType::TStrf(n) => n as usize,
+        VarType::TStrl => 8usize,
+        VarType::TByte => 1usize,
+        VarType::TInt => 2usize,
+        VarType::TLong => 4usize,
+        VarType::TFloat => 4usize,
+        VarType::TDouble => 8usize,
+    }
+}
+
+/// The variables of a sortlist, which holds 1-based variable
+/// numbers up to a 0
+fn sortlist_vars(srtlist: &[u16], nvars: usize) -> Vec<usize> {
+    srtlist
+        .iter()
+        .take_while(|&&i| i != 0)
+        .map(|&i| i as usize - 1)
+        .take_while(|&i| i < nvars)
+        .collect()
 }
@@ -1,7 +1,4 @@
-use std::{
-    mem::transmute,
-    num::{NonZeroI8, NonZeroU8},
-};
+use std::num::{NonZeroI8, NonZeroU8};
 
 use nom::{
     bytes::complete::take,
@@ -30,8 +27,8 @@ pub fn parse_int(buf: &[u8]) -> (&[u8], Value<i16>) {
     let x: IResult<&[u8], i16> = le_i16(buf);
     let (buf, i) = x.unwrap();
     let is_missing = i > 32740;
-    let missing_code = if i > 32740 {
-        let u = NonZeroU8::try_from(NonZeroI8::try_from((i - 32740) as i8).unwrap()).unwrap();
+    let missing_code = if i > 32741 {
+        let u = NonZeroU8::try_from(NonZeroI8::try_from((i - 32741) as i8).unwrap()).unwrap();
         Some(u)
     } else {
         None
@@ -44,8 +41,8 @@ pub fn parse_long(buf: &[u8]) -> (&[u8], Value<i32>) {
     let x: IResult<&[u8], i32> = le_i32(buf);
     let (buf, i) = x.unwrap();
     let is_missing = i > 2147483620;
-    let missing_code = if i > 2147483620 {
-        let u = NonZeroU8::try_from(NonZeroI8::try_from((i - 2147483620) as i8).unwrap()).unwrap();
+    let missing_code = if i > 2147483621 {
+        let u = NonZeroU8::try_from(NonZeroI8::try_from((i - 2147483621) as i8).unwrap()).unwrap();
         Some(u)
     } else {
         None
@@ -58,11 +55,12 @@ pub fn parse_float(buf: &[u8]) -> (&[u8], Value<f32>) {
     let x: IResult<&[u8], f32> = le_f32(buf);
     let (buf, f) = x.unwrap();
 
-    let i = unsafe { transmute::<f32, u32>(f) };
-    let is_missing = i >= 0x7f_00_00_00;
-    let mask = 0x3_ff;
-    let j = (i >> 10) & 0xff;
-    let missing_code = if j >= 1 && j <= 26 && i&mask == 0 {
+    let i = f.to_bits();
+    //Compare as signed so that negative values are not taken as missing
+    let is_missing = i as i32 >= 0x7f_00_00_00;
+    let mask = 0x7_ff;
+    let j = (i >> 11) & 0xff;
+    let missing_code = if (1..=26).contains(&j) && i&mask == 0 {
         Some(NonZeroU8::try_from(j as u8).unwrap())
     } else {
         None
@@ -75,11 +73,11 @@ pub fn parse_double(buf: &[u8]) -> (&[u8], Value<f64>) {
     let x: IResult<&[u8], f64> = le_f64(buf);
     let (buf, d) = x.unwrap();
 
-    let i = unsafe { transmute::<f64, u64>(d) };
-    let is_missing = i >= 0x7f_e0_00_00_00_00_00_00;
+    let i = d.to_bits();
+    let is_missing = i as i64 >= 0x7f_e0_00_00_00_00_00_00;
     let mask = 0xff_ff_ff_ff_ff;
     let j = (i >> 40) & 0xff;
-    let missing_code = if j >= 1 && j <= 26 && i&mask ==0 {
+    let missing_code = if (1..=26).contains(&j) && i&mask ==0 {
         Some(NonZeroU8::try_from(j as u8).unwrap())
     } else {
         None
@@ -101,6 +99,14 @@ pub fn parse_strlid(buf: &[u8]) -> (&[u8], (u64, u32)) {
     (buf, (o, v as u32))
 }
 
+/// Stata's notation for a missing value: `.` or `.a` to `.z`
+pub fn missing_code_str(code: Option<MissingCode>) -> String {
+    match code {
+        None => String::from("."),
+        Some(c) => format!(".{}", (b'a' + c.get() - 1) as char),
+    }
+}
+
 pub fn bytes_to_string(bs: &[u8]) -> String {
     match bs.iter().position(|&x| x == 0) {
         Some(n) => String::from_utf8(bs[..n].to_vec()).unwrap(),
@@ -0,0 +1,310 @@
+use std::borrow::Cow;
+use std::cmp::min;
+use std::io::Write;
+use std::sync::Arc;
+
+use arrow::array::{Array, AsArray};
+use arrow::datatypes::{DataType, Float32Type, Float64Type, Int16Type, Int32Type, Int64Type, Int8Type, Schema};
+use arrow::util::display::array_value_to_string;
+use arrow_array::RecordBatch;
+use rayon::prelude::*;
+
+use super::output::BatchSink;
+use super::stata::dates::{render_date, DateKind, DateStyle};
+use super::stata::{ValueLabelTable, Var};
+use super::translate::MISSING_CODE_OF;
+
+/// Rows rendered by each parallel job
+const ROWS_PER_PIECE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextLayout {
+    /// CSV, TSV and the like
+    Delimited,
+    /// One JSON object per line
+    NdJson,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quoting {
+    /// Quote fields containing the delimiter, quotes or line breaks
+    Necessary,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MissingStyle {
+    /// An empty field, or `null` in JSON
+    Empty,
+    /// `.` for all missing values
+    Dot,
+    /// `.` or `.a` to `.z` as in Stata
+    Stata,
+    /// A fixed token
+    Token(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelStyle {
+    Code,
+    Text,
+}
+
+/// Parse a value label style, `code` or `text`
+pub fn parse_label_style(s: &str) -> Result<LabelStyle, &'static str> {
+    match s.to_ascii_lowercase().as_str() {
+        "code" => Ok(LabelStyle::Code),
+        "text" => Ok(LabelStyle::Text),
+        _ => Err("Invalid value label style")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TextOptions {
+    pub delimiter: u8,
+    pub quoting: Quoting,
+    pub header: bool,
+    pub dates: DateStyle,
+    pub labels: LabelStyle,
+    pub missing: MissingStyle,
+}
+
+impl Default for TextOptions {
+    fn default() -> TextOptions {
+        TextOptions {
+            delimiter: b',',
+            quoting: Quoting::Necessary,
+            header: true,
+            dates: DateStyle::Iso,
+            labels: LabelStyle::Code,
+            missing: MissingStyle::Empty,
+        }
+    }
+}
+
+struct TextColumn {
+    name: String,
+    index: usize,
+    date: Option<DateKind>,
+    labels: Option<Arc<ValueLabelTable>>,
+    missing_codes: Option<usize>,
+}
+
+enum Rendered<'a> {
+    Null,
+    Missing(Cow<'a, str>),
+    Number(String),
+    Text(Cow<'a, str>),
+}
+
+/// Writer of delimited text or newline-delimited JSON
+///
+/// Columns marked as missing codes of another column are not
+/// written themselves, but used to render the missing values of
+/// that column in the `Stata` missing style.
+pub struct TextSink<W: Write + Send> {
+    out: W,
+    layout: TextLayout,
+    opts: TextOptions,
+    columns: Vec<TextColumn>,
+}
+
+impl<W: Write + Send> TextSink<W> {
+    pub fn new(out: W, layout: TextLayout, schema: &Schema, vars: &[Var], opts: TextOptions) -> TextSink<W> {
+        let fields = schema.fields();
+        let columns = fields
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| !f.metadata().contains_key(MISSING_CODE_OF))
+            .map(|(index, f)| {
+                let var = vars.iter().find(|v| &v.name == f.name());
+                let missing_codes = fields
+                    .iter()
+                    .position(|c| c.metadata().get(MISSING_CODE_OF) == Some(f.name()));
+                TextColumn {
+                    name: f.name().clone(),
+                    index,
+                    date: var.and_then(|v| DateKind::from_format(&v.format)),
+                    labels: var.and_then(|v| v.dictionary.clone()),
+                    missing_codes,
+                }
+            })
+            .collect();
+        let mut sink = TextSink { out, layout, opts, columns };
+        if layout == TextLayout::Delimited && sink.opts.header {
+            let mut line = Vec::new();
+            for (i, c) in sink.columns.iter().enumerate() {
+                if i > 0 {
+                    line.push(sink.opts.delimiter);
+                }
+                push_delimited(&mut line, &Rendered::Text(Cow::Borrowed(&c.name)), &sink.opts);
+            }
+            line.push(b'\n');
+            sink.out.write_all(&line).unwrap();
+        }
+        sink
+    }
+}
+
+impl<W: Write + Send> BatchSink for TextSink<W> {
+    fn write(&mut self, batch: &RecordBatch) {
+        let n = batch.num_rows();
+        let starts: Vec<usize> = (0..n).step_by(ROWS_PER_PIECE).collect();
+        let (columns, opts, layout) = (&self.columns, &self.opts, self.layout);
+        let pieces: Vec<Vec<u8>> = starts
+            .par_iter()
+            .map(|&start| {
+                let mut buf = Vec::new();
+                for row in start..min(start + ROWS_PER_PIECE, n) {
+                    render_row(&mut buf, batch, row, columns, opts, layout);
+                }
+                buf
+            })
+            .collect();
+        for p in pieces {
+            self.out.write_all(&p).unwrap();
+        }
+    }
+
+    fn finish(mut self: Box<Self>) {
+        self.out.flush().unwrap();
+    }
+}
+
+fn render_row(buf: &mut Vec<u8>, batch: &RecordBatch, row: usize, columns: &[TextColumn], opts: &TextOptions, layout: TextLayout) {
+    if layout == TextLayout::NdJson {
+        buf.push(b'{');
+    }
+    for (i, c) in columns.iter().enumerate() {
+        let r = render_cell(batch, row, c, opts);
+        match layout {
+            TextLayout::Delimited => {
+                if i > 0 {
+                    buf.push(opts.delimiter);
+                }
+                push_delimited(buf, &r, opts);
+            }
+            TextLayout::NdJson => {
+                if i > 0 {
+                    buf.push(b',');
+                }
+                push_json_string(buf, &c.name);
+                buf.push(b':');
+                push_json(buf, &r);
+            }
+        }
+    }
+    if layout == TextLayout::NdJson {
+        buf.push(b'}');
+    }
+    buf.push(b'\n');
+}
+
+fn render_cell<'a>(batch: &'a RecordBatch, row: usize, c: &TextColumn, opts: &'a TextOptions) -> Rendered<'a> {
+    let a = batch.column(c.index);
+    if a.is_null(row) {
+        return match &opts.missing {
+            MissingStyle::Empty => Rendered::Null,
+            MissingStyle::Dot => Rendered::Missing(Cow::Borrowed(".")),
+            MissingStyle::Token(t) => Rendered::Missing(Cow::Borrowed(t)),
+            MissingStyle::Stata => {
+                let code = c
+                    .missing_codes
+                    .map(|i| batch.column(i).as_string::<i32>())
+                    .filter(|codes| codes.is_valid(row))
+                    .map(|codes| codes.value(row));
+                Rendered::Missing(Cow::Borrowed(code.unwrap_or(".")))
+            }
+        };
+    }
+    let int = match a.data_type() {
+        DataType::Int8 => Some(a.as_primitive::<Int8Type>().value(row) as i64),
+        DataType::Int16 => Some(a.as_primitive::<Int16Type>().value(row) as i64),
+        DataType::Int32 => Some(a.as_primitive::<Int32Type>().value(row) as i64),
+        DataType::Int64 => Some(a.as_primitive::<Int64Type>().value(row)),
+        _ => None,
+    };
+    let x = match (int, a.data_type()) {
+        (Some(i), _) => i as f64,
+        (None, DataType::Float32) => a.as_primitive::<Float32Type>().value(row) as f64,
+        (None, DataType::Float64) => a.as_primitive::<Float64Type>().value(row),
+        (None, DataType::Utf8) => return Rendered::Text(Cow::Borrowed(a.as_string::<i32>().value(row))),
+        (None, DataType::Binary) => {
+            let s = a.as_binary::<i32>().value(row);
+            let s = s.strip_suffix(b"\0").unwrap_or(s);
+            return Rendered::Text(String::from_utf8_lossy(s));
+        }
+        _ => return Rendered::Text(Cow::Owned(array_value_to_string(a, row).unwrap())),
+    };
+    if opts.labels == LabelStyle::Text && x.fract() == 0.0 {
+        if let Some(l) = c.labels.as_ref().and_then(|t| t.label(x as i32)) {
+            return Rendered::Text(Cow::Owned(l.to_string()));
+        }
+    }
+    if let Some(kind) = c.date {
+        if opts.dates != DateStyle::Raw {
+            return Rendered::Text(Cow::Owned(render_date(kind, x, opts.dates)));
+        }
+    }
+    match (int, a.data_type()) {
+        (Some(i), _) => Rendered::Number(format!("{}", i)),
+        //Keep the shortest representation of the stored float
+        (None, DataType::Float32) => Rendered::Number(format!("{}", a.as_primitive::<Float32Type>().value(row))),
+        _ => Rendered::Number(format!("{}", x)),
+    }
+}
+
+fn push_delimited(buf: &mut Vec<u8>, r: &Rendered, opts: &TextOptions) {
+    let s: &str = match r {
+        Rendered::Null => return,
+        Rendered::Missing(s) | Rendered::Text(s) => s,
+        Rendered::Number(s) => s,
+    };
+    let quote = match opts.quoting {
+        Quoting::Always => true,
+        Quoting::Never => false,
+        Quoting::Necessary => s
+            .bytes()
+            .any(|b| b == opts.delimiter || b == b'"' || b == b'\n' || b == b'\r'),
+    };
+    if quote {
+        buf.push(b'"');
+        for b in s.bytes() {
+            if b == b'"' {
+                buf.push(b'"');
+            }
+            buf.push(b);
+        }
+        buf.push(b'"');
+    } else {
+        buf.extend_from_slice(s.as_bytes());
+    }
+}
+
+fn push_json(buf: &mut Vec<u8>, r: &Rendered) {
+    match r {
+        Rendered::Null => buf.extend_from_slice(b"null"),
+        Rendered::Number(s) => buf.extend_from_slice(s.as_bytes()),
+        Rendered::Missing(s) | Rendered::Text(s) => push_json_string(buf, s),
+    }
+}
+
+fn push_json_string(buf: &mut Vec<u8>, s: &str) {
+    buf.push(b'"');
+    for c in s.chars() {
+        match c {
+            '"' => buf.extend_from_slice(b"\\\""),
+            '\\' => buf.extend_from_slice(b"\\\\"),
+            '\n' => buf.extend_from_slice(b"\\n"),
+            '\r' => buf.extend_from_slice(b"\\r"),
+            '\t' => buf.extend_from_slice(b"\\t"),
+            c if (c as u32) < 0x20 => buf.extend_from_slice(format!("\\u{:04x}", c as u32).as_bytes()),
+            c => {
+                let mut tmp = [0u8; 4];
+                buf.extend_from_slice(c.encode_utf8(&mut tmp).as_bytes());
+            }
+        }
+    }
+    buf.push(b'"');
+}
@@ -1,12 +1,74 @@
-use arrow::datatypes::{DataType, Field, Schema};
+use std::collections::HashMap;
+use std::sync::Arc;
 
+use arrow::array::{Array, ArrayRef, AsArray, Date32Array, StringArray, TimestampMillisecondArray};
+use arrow::compute::{cast, cast_with_options, CastOptions};
+use arrow::datatypes::{DataType, Field, Float64Type, Schema, SchemaRef, TimeUnit, DECIMAL128_MAX_PRECISION};
+use arrow::util::display::array_value_to_string;
+use arrow_array::RecordBatch;
+use chrono::NaiveDate;
+use parquet::basic::{Compression, Encoding};
+use rayon::prelude::*;
+
+use super::output::BatchSink;
+use super::parquet::ParquetOptions;
+use super::rename::NameTransform;
+use super::stata::dates::{period_start, DateKind};
 use super::stata::{Var, VarType};
+use super::text::LabelStyle;
+
+/// Milliseconds from 01jan1960, Stata's epoch, to 01jan1970
+const STATA_EPOCH_MILLIS: i64 = 315_619_200_000;
+
+/// How to write variables with a date format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateHandling {
+    /// The number as stored
+    Raw,
+    /// Arrow dates, or timestamps for `%tc`. Weeks, months and
+    /// so on become the date they start on.
+    Native,
+}
 
+/// How to write the missing values of a numeric variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingPolicy {
+    /// Null
+    Null,
+    /// Null, with the Stata missing code in a `{name}_missing` column
+    Codes,
+}
+
+/// Settings for the variables whose name matches `pattern`
+#[derive(Debug, Clone, Default)]
+pub struct VariableRule {
+    pub pattern: glob::Pattern,
+    pub include: Option<bool>,
+    /// Output name, where `{name}` stands for the variable name
+    pub name: Option<String>,
+    pub data_type: Option<DataType>,
+    /// Write as `Decimal128` with the scale of the display format
+    pub decimal: bool,
+    pub dates: Option<DateHandling>,
+    pub labels: Option<LabelStyle>,
+    pub missing: Option<MissingPolicy>,
+    pub compression: Option<Compression>,
+    pub encoding: Option<Encoding>,
+}
+
+/// How a variable is written
+#[derive(Debug, Clone)]
 pub struct TranslateSpec {
     pub name: String,
     pub in_type: VarType,
     pub include: bool,
-    pub compression: parquet::basic::Compression,
+    /// Type to cast the column to at the end
+    pub out_type: Option<DataType>,
+    pub dates: DateHandling,
+    pub labels: LabelStyle,
+    pub missing: MissingPolicy,
+    pub compression: Option<Compression>,
+    pub encoding: Option<Encoding>,
 }
 
 impl TranslateSpec {
@@ -14,11 +76,138 @@ impl TranslateSpec {
         TranslateSpec{
             name: v.name.clone(),
             in_type: v.ty,
-            include: false,
-            compression: parquet::basic::Compression::UNCOMPRESSED,
+            include: true,
+            out_type: None,
+            dates: DateHandling::Raw,
+            labels: LabelStyle::Code,
+            missing: MissingPolicy::Null,
+            compression: None,
+            encoding: None,
+        }
+    }
+
+    /// The spec of `v` after the rules matching its name, later rules
+    /// taking precedence. It is written as `name` unless a rule gives
+    /// another name.
+    pub fn from_rules(v: &Var, rules: &[VariableRule], name: String) -> TranslateSpec {
+        let mut spec = TranslateSpec::new(v);
+        spec.name = name;
+        for r in rules.iter().filter(|r| r.pattern.matches(&v.name)) {
+            if let Some(i) = r.include {
+                spec.include = i;
+            }
+            if let Some(n) = &r.name {
+                spec.name = n.replace("{name}", &v.name);
+            }
+            if let Some(t) = &r.data_type {
+                spec.out_type = Some(t.clone());
+            }
+            if r.decimal {
+                let t = decimal_type(&v.format)
+                    .unwrap_or_else(|| panic!("{} has format {}, which gives no decimal places", v.name, v.format));
+                spec.out_type = Some(t);
+            }
+            spec.dates = r.dates.unwrap_or(spec.dates);
+            spec.labels = r.labels.unwrap_or(spec.labels);
+            spec.missing = r.missing.unwrap_or(spec.missing);
+            spec.compression = r.compression.or(spec.compression);
+            spec.encoding = r.encoding.or(spec.encoding);
+        }
+        spec
+    }
+}
+
+/// Wider types for systems that lack the narrow ones, for
+/// the variables not given a type of their own
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Widening {
+    /// Integers to `Int64`
+    pub integers: bool,
+    /// Floats to `Float64`
+    pub floats: bool,
+    /// Strings to `LargeUtf8`
+    pub strings: bool,
+}
+
+impl Widening {
+    fn widen(&self, ty: &DataType) -> Option<DataType> {
+        match ty {
+            DataType::Int8 | DataType::Int16 | DataType::Int32 if self.integers => Some(DataType::Int64),
+            DataType::Float32 if self.floats => Some(DataType::Float64),
+            DataType::Utf8 if self.strings => Some(DataType::LargeUtf8),
+            _ => None,
         }
     }
-}  
+}
+
+/// Decimal type for a fixed display format such as `%12.2f` or `%9.0fc`,
+/// with the scale of the format and a precision of at least 18 digits
+pub fn decimal_type(format: &str) -> Option<DataType> {
+    let f = format.strip_prefix('%')?;
+    let f = f.strip_prefix('-').unwrap_or(f);
+    let f = f.strip_suffix('c').unwrap_or(f).strip_suffix('f')?;
+    let (width, places) = f.split_once('.')?;
+    let width: u8 = width.parse().ok()?;
+    let places: u8 = places.parse().ok()?;
+    let precision = width.max(18).max(places.saturating_add(1)).min(DECIMAL128_MAX_PRECISION);
+    (places < precision).then_some(DataType::Decimal128(precision, places as i8))
+}
+
+/// Parse a date handling, `raw` or `native`
+pub fn parse_date_handling(s: &str) -> Result<DateHandling, &'static str> {
+    match s.to_ascii_lowercase().as_str() {
+        "raw" => Ok(DateHandling::Raw),
+        "native" => Ok(DateHandling::Native),
+        _ => Err("Invalid date handling"),
+    }
+}
+
+/// Parse a missing value policy, `null` or `codes`
+pub fn parse_missing_policy(s: &str) -> Result<MissingPolicy, &'static str> {
+    match s.to_ascii_lowercase().as_str() {
+        "null" => Ok(MissingPolicy::Null),
+        "codes" => Ok(MissingPolicy::Codes),
+        _ => Err("Invalid missing value policy"),
+    }
+}
+
+/// Parse an Arrow type name such as `int32`, `float64`, `utf8`, `date32`
+/// or `decimal128(12,2)`
+pub fn parse_data_type(s: &str) -> Result<DataType, &'static str> {
+    Ok(match s.to_ascii_lowercase().as_str() {
+        "bool" | "boolean" => DataType::Boolean,
+        "int8" => DataType::Int8,
+        "int16" => DataType::Int16,
+        "int32" => DataType::Int32,
+        "int64" => DataType::Int64,
+        "uint8" => DataType::UInt8,
+        "uint16" => DataType::UInt16,
+        "uint32" => DataType::UInt32,
+        "uint64" => DataType::UInt64,
+        "float32" => DataType::Float32,
+        "float64" => DataType::Float64,
+        "utf8" | "string" => DataType::Utf8,
+        "large_utf8" | "large_string" => DataType::LargeUtf8,
+        "binary" => DataType::Binary,
+        "large_binary" => DataType::LargeBinary,
+        "date32" => DataType::Date32,
+        "timestamp" => DataType::Timestamp(TimeUnit::Millisecond, None),
+        t => {
+            let args = t
+                .strip_prefix("decimal128(")
+                .or_else(|| t.strip_prefix("decimal("))
+                .and_then(|t| t.strip_suffix(')'))
+                .ok_or("Invalid type")?;
+            let (p, s) = args.split_once(',').ok_or("Decimal types are written as decimal128(P,S)")?;
+            let p: u8 = p.trim().parse().map_err(|_| "Invalid decimal precision")?;
+            let s: i8 = s.trim().parse().map_err(|_| "Invalid decimal scale")?;
+            if p == 0 || p > DECIMAL128_MAX_PRECISION || s < 0 || s as u8 > p {
+                return Err("Decimal precision must be 1 to 38, and scale 0 to the precision");
+            }
+            DataType::Decimal128(p, s)
+        }
+    })
+}
 
 pub fn make_schema(vars: &[Var]) -> Schema {
     let mut fields: Vec<Field> = Vec::new();
@@ -35,6 +224,363 @@ pub fn make_schema(vars: &[Var]) -> Schema {
         };
         fields.push(Field::new(&v.name, aty, true));
     }
-    return Schema::new(fields);
+    Schema::new(fields)
+}
+
+
+/// Field metadata key holding the Stata name of the variable of a column
+pub const VARIABLE_NAME: &str = "dta2pqt.variable";
+
+/// Field metadata key holding the variable label of a column
+pub const VARIABLE_LABEL: &str = "dta2pqt.label";
+
+/// Field metadata key marking a column of extended missing value codes.
+/// The value is the name of the variable the codes belong to.
+pub const MISSING_CODE_OF: &str = "dta2pqt.missing_code_of";
+
+/// A column holding `.` or `.a` to `.z` where the numeric variable `v`
+/// is missing, and null elsewhere. Strings have no missing values.
+pub fn missing_code_field(v: &Var) -> Option<Field> {
+    match v.ty {
+        VarType::TStrf(_) | VarType::TASCII(_) | VarType::TStrl => None,
+        _ => Some(
+            Field::new(format!("{}_missing", v.name), DataType::Utf8, true)
+                .with_metadata(HashMap::from([(MISSING_CODE_OF.to_string(), v.name.clone())])),
+        ),
+    }
+}
+
+/// Like `make_schema`, but followed by a missing code column for
+/// each numeric variable
+pub fn make_schema_with_missing_codes(vars: &[Var]) -> Schema {
+    let schema = make_schema(vars);
+    let mut fields: Vec<Field> = schema.fields().iter().map(|f| f.as_ref().clone()).collect();
+    fields.extend(vars.iter().filter_map(missing_code_field));
+    Schema::new(fields)
+}
+
+/// Schema metadata key naming the columns the rows are sorted by,
+/// separated by commas
+pub const SORTED_BY: &str = "dta2pqt.sorted_by";
+
+/// Schema metadata recording that the rows are sorted by `columns`
+pub fn sorted_by_metadata(columns: &[&str]) -> HashMap<String, String> {
+    HashMap::from([(SORTED_BY.to_string(), columns.join(","))])
+}
+
+/// The columns the rows of a schema are sorted by
+pub fn sorted_by(schema: &Schema) -> Vec<&str> {
+    match schema.metadata().get(SORTED_BY) {
+        Some(s) if !s.is_empty() => s.split(',').collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The conversion of batches as parsed, laid out as by `make_schema`
+/// or `make_schema_with_missing_codes`, to the output columns
+///
+/// Excluded variables are dropped and the others renamed, with value
+/// labels, dates and types converted by their `TranslateSpec`, and the
+/// types of the others widened by `widening`. Missing
+/// code columns come after the variables, for the variables that want
+/// them or for all if `all_codes`. Columns after those of the parsed
+/// layout are passed through at the end.
+pub struct Translation {
+    specs: Vec<TranslateSpec>,
+    vars: Vec<Var>,
+    /// The output columns that are variables, with their output names,
+    /// and formats and value labels dropped where converted
+    out_vars: Vec<Var>,
+    /// Index of each output variable in `vars`
+    sources: Vec<usize>,
+    conversions: Vec<Conversion>,
+    /// Type of each output variable before the cast to its `out_type`
+    converted: Vec<DataType>,
+    /// Indices in `vars` of variables with an output missing code column
+    codes: Vec<usize>,
+    /// Whether the parsed batches have missing code columns
+    parsed_codes: bool,
+    schema: Schema,
 }
 
+impl Translation {
+    pub fn new(vars: &[Var], rules: &[VariableRule], names: &NameTransform, widening: Widening, all_codes: bool) -> Translation {
+        let mut specs: Vec<TranslateSpec> = vars
+            .iter()
+            .zip(names.names(vars))
+            .map(|(v, n)| TranslateSpec::from_rules(v, rules, n))
+            .collect();
+        let mut seen: HashMap<&str, &str> = HashMap::new();
+        for (v, s) in vars.iter().zip(&specs).filter(|(_, s)| s.include) {
+            if s.name.is_empty() {
+                panic!("Variable {} would be written with an empty name", v.name);
+            }
+            if let Some(prev) = seen.insert(&s.name, &v.name) {
+                panic!("Variables {} and {} would both be written as {}", prev, v.name, s.name);
+            }
+        }
+        let sources: Vec<usize> = (0..vars.len()).filter(|&i| specs[i].include).collect();
+        let codes: Vec<usize> = sources
+            .iter()
+            .copied()
+            .filter(|&i| missing_code_field(&vars[i]).is_some())
+            .filter(|&i| all_codes || specs[i].missing == MissingPolicy::Codes)
+            .collect();
+        let raw = make_schema(vars);
+        let mut out_vars = Vec::new();
+        let mut conversions = Vec::new();
+        let mut converted = Vec::new();
+        let mut fields = Vec::new();
+        for &i in &sources {
+            let (v, s) = (&vars[i], &mut specs[i]);
+            let mut out = v.clone();
+            out.name = s.name.clone();
+            let mut ty = raw.field(i).data_type().clone();
+            let date = DateKind::from_format(&v.format).filter(|_| missing_code_field(v).is_some());
+            let conversion = if s.labels == LabelStyle::Text && v.dictionary.is_some() {
+                ty = DataType::Utf8;
+                out.dictionary = None;
+                Conversion::Labels
+            } else if let Some(kind) = date.filter(|_| s.dates == DateHandling::Native) {
+                ty = match kind {
+                    DateKind::Millis => DataType::Timestamp(TimeUnit::Millisecond, None),
+                    _ => DataType::Date32,
+                };
+                out.format = String::new();
+                Conversion::Dates(kind)
+            } else {
+                Conversion::None
+            };
+            conversions.push(conversion);
+            converted.push(ty.clone());
+            if s.out_type.is_none() {
+                s.out_type = widening.widen(&ty);
+            }
+            if let Some(t) = &s.out_type {
+                //Numbers keep their meaning, and so their format
+                if !(ty.is_numeric() && t.is_numeric()) {
+                    out.format = String::new();
+                }
+                ty = t.clone();
+            }
+            let mut metadata = HashMap::from([(VARIABLE_NAME.to_string(), v.name.clone())]);
+            if !v.var_label.is_empty() {
+                metadata.insert(VARIABLE_LABEL.to_string(), v.var_label.clone());
+            }
+            fields.push(Field::new(&out.name, ty, true).with_metadata(metadata));
+            out_vars.push(out);
+        }
+        for &i in &codes {
+            let out = &out_vars[sources.iter().position(|&j| j == i).unwrap()];
+            let f = missing_code_field(out).unwrap();
+            if let Some(k) = out_vars.iter().position(|o| o.name == *f.name()) {
+                let other = &vars[sources[k]].name;
+                panic!("The missing codes of {} would be written as {}, which is the name of {}", vars[i].name, f.name(), other);
+            }
+            fields.push(f);
+        }
+        let parsed_codes = !codes.is_empty();
+        Translation {
+            specs,
+            vars: vars.to_vec(),
+            out_vars,
+            sources,
+            conversions,
+            converted,
+            codes,
+            parsed_codes,
+            schema: Schema::new(fields),
+        }
+    }
+
+    pub fn specs(&self) -> &[TranslateSpec] {
+        &self.specs
+    }
+
+    /// The output schema
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// The variables written, under their output names
+    pub fn out_vars(&self) -> &[Var] {
+        &self.out_vars
+    }
+
+    /// Index of each output variable among the input variables
+    pub fn sources(&self) -> &[usize] {
+        &self.sources
+    }
+
+    /// Whether the parsed batches must have missing code columns
+    pub fn needs_missing_codes(&self) -> bool {
+        self.parsed_codes
+    }
+
+    /// Whether the value label tables must be read
+    pub fn needs_value_labels(&self) -> bool {
+        self.sources
+            .iter()
+            .any(|&i| self.specs[i].labels == LabelStyle::Text && !self.vars[i].value_label.is_empty())
+    }
+
+    /// The schema of the parsed batches
+    pub fn parsed_schema(&self) -> Schema {
+        if self.parsed_codes {
+            make_schema_with_missing_codes(&self.vars)
+        } else {
+            make_schema(&self.vars)
+        }
+    }
+
+    /// The output name of variable `i`, if it is written
+    pub fn out_name(&self, i: usize) -> Option<&str> {
+        self.sources.contains(&i).then_some(self.specs[i].name.as_str())
+    }
+
+    /// The output name of variable `i`, if it is written with its
+    /// values in the same order, so that a sort by it still holds
+    pub fn sorted_name(&self, i: usize) -> Option<&str> {
+        let j = self.sources.iter().position(|&k| k == i)?;
+        let from = &self.converted[j];
+        let keeps_order = match &self.specs[i].out_type {
+            None => true,
+            Some(to) if from.is_numeric() => to.is_numeric(),
+            Some(DataType::LargeUtf8) => *from == DataType::Utf8,
+            Some(DataType::Dictionary(_, values)) => values.as_ref() == from,
+            Some(_) => false,
+        };
+        match self.conversions[j] {
+            Conversion::Labels => None,
+            _ => Some(self.specs[i].name.as_str()).filter(|_| keeps_order),
+        }
+    }
+
+    /// Add the compression and encoding of the specs to the column
+    /// settings that do not have them
+    pub fn column_options(&self, p: &mut ParquetOptions) {
+        for &i in &self.sources {
+            let s = &self.specs[i];
+            if s.compression.is_none() && s.encoding.is_none() {
+                continue;
+            }
+            let col = p.columns.entry(s.name.clone()).or_default();
+            col.compression = col.compression.or(s.compression);
+            col.encoding = col.encoding.or(s.encoding);
+        }
+    }
+
+    /// Convert a parsed batch to the output columns
+    pub fn translate(&self, batch: &RecordBatch, schema: &SchemaRef) -> RecordBatch {
+        let nvars = self.vars.len();
+        let width = if self.parsed_codes { self.parsed_schema().fields().len() } else { nvars };
+        let mut columns: Vec<ArrayRef> = self
+            .sources
+            .par_iter()
+            .zip(&self.out_vars)
+            .enumerate()
+            .map(|(j, (&i, _))| self.convert(batch.column(i), i, self.conversions[j], self.schema.field(j).data_type()))
+            .collect();
+        for &i in &self.codes {
+            let k = nvars + self.vars[..i].iter().filter(|v| missing_code_field(v).is_some()).count();
+            columns.push(batch.column(k).clone());
+        }
+        columns.extend(batch.columns()[width..].iter().cloned());
+        RecordBatch::try_new(schema.clone(), columns).unwrap()
+    }
+
+    fn convert(&self, a: &ArrayRef, i: usize, conversion: Conversion, ty: &DataType) -> ArrayRef {
+        let v = &self.vars[i];
+        let mut a = match conversion {
+            Conversion::None => a.clone(),
+            Conversion::Labels => label_strings(a, v),
+            Conversion::Dates(kind) => native_dates(a, kind),
+        };
+        if a.data_type() != ty {
+            //Values the type cannot hold are an error rather than null
+            let options = CastOptions { safe: false, ..Default::default() };
+            a = cast_with_options(&a, ty, &options)
+                .unwrap_or_else(|e| panic!("{} cannot be written as {}: {}", v.name, ty, e));
+        }
+        a
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Conversion {
+    None,
+    /// Value labels in place of values
+    Labels,
+    Dates(DateKind),
+}
+
+/// The value labels of a numeric column, or the values where unlabelled
+fn label_strings(a: &ArrayRef, v: &Var) -> ArrayRef {
+    let table = v.dictionary.as_ref().unwrap();
+    let x = cast(a, &DataType::Float64).unwrap();
+    let x = x.as_primitive::<Float64Type>();
+    let labels: StringArray = (0..a.len())
+        .map(|row| {
+            x.is_valid(row).then(|| {
+                let value = x.value(row);
+                match table.label(value as i32).filter(|_| value.fract() == 0.0) {
+                    Some(l) => l.to_string(),
+                    None => array_value_to_string(a, row).unwrap(),
+                }
+            })
+        })
+        .collect();
+    Arc::new(labels)
+}
+
+/// Arrow dates or timestamps for the stored values of a date variable
+fn native_dates(a: &ArrayRef, kind: DateKind) -> ArrayRef {
+    let x = cast(a, &DataType::Float64).unwrap();
+    let x = x.as_primitive::<Float64Type>();
+    match kind {
+        DateKind::Millis => Arc::new(
+            x.iter()
+                .map(|v| (v.filter(|v| v.is_finite())?.floor() as i64).checked_sub(STATA_EPOCH_MILLIS))
+                .collect::<TimestampMillisecondArray>(),
+        ),
+        _ => {
+            let unix_epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+            Arc::new(
+                x.iter()
+                    .map(|v| {
+                        let d = period_start(kind, v.filter(|v| v.is_finite())?.floor() as i64)?;
+                        i32::try_from((d - unix_epoch).num_days()).ok()
+                    })
+                    .collect::<Date32Array>(),
+            )
+        }
+    }
+}
+
+/// Translates batches before writing them to `inner`
+pub struct TranslateSink<'a> {
+    inner: Box<dyn BatchSink + 'a>,
+    translation: Translation,
+    /// The schema of the inner sink
+    schema: SchemaRef,
+}
+
+impl<'a> TranslateSink<'a> {
+    pub fn new(inner: Box<dyn BatchSink + 'a>, translation: Translation, schema: SchemaRef) -> TranslateSink<'a> {
+        TranslateSink { inner, translation, schema }
+    }
+}
+
+impl BatchSink for TranslateSink<'_> {
+    fn write(&mut self, batch: &RecordBatch) {
+        self.inner.write(&self.translation.translate(batch, &self.schema));
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.inner.bytes_written()
+    }
+
+    fn finish(self: Box<Self>) {
+        self.inner.finish();
+    }
+}